@@ -1,12 +1,55 @@
-use std::env;
+use std::{
+    env,
+    io::{self, Read},
+};
 
-use htmlua_parser::serve::serve_content;
+use htmlua_parser::{
+    request::Request,
+    serve::{error_response, serve_content},
+};
 
 fn main() {
-    println!("Content-Type: text/html\n");
+    let request = request_from_env();
+    let response = serve_content(&request).unwrap_or_else(|e| {
+        eprintln!("htmlua: {} {}: {e}", request.method, request.path);
+        error_response(&e)
+    });
 
+    println!("Status: {} {}", response.status, response.reason_phrase());
+    for (name, value) in &response.headers {
+        println!("{name}: {value}");
+    }
+    println!();
+    print!("{}", response.body);
+}
+
+fn request_from_env() -> Request {
+    let method = env::var("REQUEST_METHOD").unwrap_or_else(|_| "GET".to_string());
     let request_uri = env::var("PATH_INFO").unwrap_or_else(|_| "".to_string());
-    let page = serve_content(request_uri.as_str()).unwrap();
+    let mut request = Request::new(&method, &request_uri);
+    request.query_string = env::var("QUERY_STRING").unwrap_or_default();
+
+    for (key, value) in env::vars() {
+        if let Some(name) = key.strip_prefix("HTTP_") {
+            request.add_header(&name.replace('_', "-"), &value);
+        }
+    }
+    // Apache passes these two without the HTTP_ prefix.
+    for (key, name) in [("CONTENT_TYPE", "content-type"), ("CONTENT_LENGTH", "content-length")] {
+        if let Ok(value) = env::var(key) {
+            request.add_header(name, &value);
+        }
+    }
 
-    println!("{page}");
+    let content_length = env::var("CONTENT_LENGTH")
+        .ok()
+        .and_then(|l| l.parse::<u64>().ok())
+        .unwrap_or(0);
+    if content_length > 0 {
+        let mut body = Vec::new();
+        if io::stdin().take(content_length).read_to_end(&mut body).is_ok() {
+            request.body = String::from_utf8_lossy(&body).into_owned();
+        }
+    }
+    request
 }
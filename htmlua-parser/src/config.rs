@@ -5,9 +5,19 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
+    /// Shows Lua errors as a detailed overlay with source context instead of the configured error pages. Meant for
+    /// development only, since it exposes source code and file paths.
+    #[serde(default)]
+    pub dev_mode: bool,
     pub paths: PathConfig,
     pub server: ServerConfig,
     pub syntax_highlighting: SyntaxConfig,
+    #[serde(default)]
+    pub lua: LuaConfig,
+    #[serde(default)]
+    pub templates: TemplateConfig,
+    #[serde(default)]
+    pub error_pages: ErrorPagesConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -15,36 +25,140 @@ pub struct PathConfig {
     pub pages: PathBuf,
     pub components: PathBuf,
     pub themes: PathBuf,
+    #[serde(default = "default_lua_modules_path")]
+    pub lua_modules: PathBuf,
+    /// Source files `<syntaxhighlight src="...">` reads code from.
+    #[serde(default = "default_snippets_path")]
+    pub snippets: PathBuf,
 }
 
+fn default_lua_modules_path() -> PathBuf { PathBuf::from("/var/www/htmlua/lua") }
+
+fn default_snippets_path() -> PathBuf { PathBuf::from("/var/www/htmlua/snippets") }
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    /// Requests with a longer body are refused with 413 Payload Too Large.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
 }
 
+fn default_max_body_bytes() -> u64 { 1024 * 1024 }
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SyntaxConfig {
     pub default_theme: String,
     pub load_custom_themes: bool,
+    /// Emits `hl-` prefixed classes instead of inline styles, for use with a theme stylesheet from
+    /// `/_htmlua/theme/<name>.css`. Pages can then follow a `style-src` policy without `'unsafe-inline'`.
+    /// The stylesheet picks the theme, so a `theme` attribute on `<syntaxhighlight>` is ignored with a warning.
+    #[serde(default)]
+    pub css_classes: bool,
+    /// Precompiled syntect dumps loaded instead of the bundled syntaxes and themes, which cuts the start-up time of
+    /// CGI processes. Write them with `Renderer::dump_highlighting_assets`; the syntax dump must use newline syntaxes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub syntax_dump: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme_dump: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LuaConfig {
+    /// Restricts `<lua>` blocks to `libraries` and strips filesystem and process access from the globals.
+    pub sandbox: bool,
+    pub libraries: Vec<String>,
+    /// Limits below are per render; `0` disables a limit.
+    pub memory_limit_bytes: usize,
+    pub instruction_limit: u64,
+    pub timeout_ms: u64,
+    /// Lua file run in each page's Lua state before its first block, e.g. to `htmlua.define_tag` shared widgets.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub startup_script: Option<PathBuf>,
+}
+
+impl Default for LuaConfig {
+    fn default() -> Self {
+        Self {
+            sandbox: true,
+            libraries: ["coroutine", "math", "os", "string", "table", "utf8"]
+                .map(String::from)
+                .to_vec(),
+            memory_limit_bytes: 64 * 1024 * 1024,
+            instruction_limit: 100_000_000,
+            timeout_ms: 5_000,
+            startup_script: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct TemplateConfig {
+    pub max_include_depth: usize,
+    /// Render stages in the order they run: any of `include`, `lua`, `markdown`, `highlight`, `tags` (custom tags
+    /// registered on the renderer) and `footnotes`.
+    pub pipeline: Vec<String>,
+}
+
+impl Default for TemplateConfig {
+    fn default() -> Self {
+        Self {
+            max_include_depth: 32,
+            pipeline: ["include", "markdown", "highlight", "lua", "tags", "footnotes"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+}
+
+/// HTML files served for each kind of render error. Errors without a page of their own fall back to `default`, then
+/// to a built-in page.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ErrorPagesConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_found: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forbidden: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lua_error: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_error: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_error: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<PathBuf>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            dev_mode: false,
             paths: PathConfig {
                 pages: PathBuf::from("/var/www/htmlua/pages"),
                 components: PathBuf::from("/var/www/htmlua/components"),
                 themes: PathBuf::from("/var/www/htmlua/themes"),
+                lua_modules: default_lua_modules_path(),
+                snippets: default_snippets_path(),
             },
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
                 port: 8080,
+                max_body_bytes: default_max_body_bytes(),
             },
             syntax_highlighting: SyntaxConfig {
                 default_theme: "base16-ocean.dark".to_string(),
                 load_custom_themes: true,
+                css_classes: false,
+                syntax_dump: None,
+                theme_dump: None,
             },
+            lua: LuaConfig::default(),
+            templates: TemplateConfig::default(),
+            error_pages: ErrorPagesConfig::default(),
         }
     }
 }
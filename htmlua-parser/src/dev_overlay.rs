@@ -0,0 +1,110 @@
+use std::fmt::Write;
+
+use crate::{
+    error::{LuaBlockError, RenderError},
+    helpers::{escape_html, source_label},
+    render::highlight_code,
+    renderer::Renderer,
+};
+
+/// Lines of source shown on each side of the failing line.
+const CONTEXT_LINES: usize = 5;
+
+const STYLE: &str = "body{margin:0;font-family:system-ui,sans-serif;background:#1e1e1e;color:#eee}\
+main{max-width:960px;margin:2rem auto;padding:0 1rem}h1{color:#ff6b6b;font-size:1.4rem}\
+.location{color:#aaa}pre{background:#2b303b;padding:1rem;overflow-x:auto;border-radius:4px}\
+.source .line{display:block}.source .lineno{display:inline-block;width:4ch;margin-right:1ch;color:#777;\
+text-align:right;user-select:none}.source .error{background:#5c2b2b}";
+
+/// Renders a page describing `error` for development: the file and line a Lua error came from, the highlighted
+/// source around it and the Lua traceback.
+#[must_use]
+pub fn render_error_overlay(error: &RenderError, renderer: &Renderer) -> String {
+    let mut body = String::new();
+    match error {
+        RenderError::LuaBlockError(block_error) => write_lua_block_error(&mut body, block_error, renderer),
+        error => {
+            let _ = write!(body, "<h1>{}</h1>", escape_html(&error.to_string()));
+        }
+    }
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>htmlua error</title><style>{STYLE}</style>\
+         </head><body><main>{body}</main></body></html>\n"
+    )
+}
+
+fn write_lua_block_error(out: &mut String, error: &LuaBlockError, renderer: &Renderer) {
+    let _ = write!(out, "<h1>Lua error: {}</h1>", escape_html(&error.message));
+    if let Some(file) = &error.source_file {
+        let mut location = source_label(file, renderer.config());
+        if let Some(line) = error.line.or(error.block_line) {
+            let _ = write!(location, ":{line}");
+        }
+        if let Some(block) = error.block {
+            let _ = write!(location, " (block {block})");
+        }
+        let _ = write!(out, r#"<p class="location">{}</p>"#, escape_html(&location));
+    }
+    write_source_context(out, error, renderer);
+    if let Some(traceback) = &error.traceback {
+        let _ = write!(out, r#"<pre class="traceback">{}</pre>"#, escape_html(traceback));
+    }
+}
+
+/// Shows the block's code with file line numbers, limited to the lines around the error when it has one.
+fn write_source_context(out: &mut String, error: &LuaBlockError, renderer: &Renderer) {
+    let lines = highlight_code(renderer, &error.code, "lua")
+        .unwrap_or_else(|_| error.code.split_inclusive('\n').map(escape_html).collect());
+    let first_line = error.block_line.unwrap_or(1);
+    let (start, end) = match error.line.map(|line| line.saturating_sub(first_line)) {
+        Some(index) if index < lines.len() => {
+            (index.saturating_sub(CONTEXT_LINES), (index + CONTEXT_LINES + 1).min(lines.len()))
+        }
+        _ => (0, lines.len()),
+    };
+    out.push_str(r#"<pre class="source"><code>"#);
+    for (index, line) in lines.iter().enumerate().take(end).skip(start) {
+        let number = first_line + index;
+        let class = if error.line == Some(number) {
+            "line error"
+        } else {
+            "line"
+        };
+        let _ = write!(
+            out,
+            r#"<span class="{class}"><span class="lineno">{number}</span>{}</span>"#,
+            line.trim_end_matches('\n')
+        );
+    }
+    out.push_str("</code></pre>");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn lua_block_overlay() {
+        let mut config = Config::default();
+        config.paths.components = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/components");
+        let file = config.paths.components.canonicalize().unwrap().join("broken.html");
+        let error = LuaBlockError {
+            message: "components/broken.html:10:3: attempt to call a nil value (global 'missing')".to_string(),
+            traceback: Some("stack traceback:\n\t[C]: in ?".to_string()),
+            code: "\nlocal x = 1\nmissing()\n".to_string(),
+            source_file: Some(file),
+            block: Some(2),
+            block_line: Some(10),
+            line: Some(12),
+        };
+        let renderer = Renderer::new(config).unwrap();
+        let html = render_error_overlay(&RenderError::LuaBlockError(Box::new(error)), &renderer);
+        assert!(html.contains("components/broken.html:12 (block 2)"));
+        assert!(html.contains("stack traceback:"));
+        assert!(html.contains(r#"<span class="line error"><span class="lineno">12</span>"#));
+        assert!(html.contains(r#"<span class="lineno">11</span>"#));
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    fmt::{self, Display},
+    io,
+    path::PathBuf,
+};
+
+use thiserror::Error;
+
+pub type Result<T, E = RenderError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("Not found: {}", .0.display())]
+    NotFound(PathBuf),
+    /// The path resolves outside the root it was requested from.
+    #[error("Forbidden: {} is outside its root", .0.display())]
+    Forbidden(PathBuf),
+    #[error("Lua error: {0}")]
+    LuaError(String),
+    /// A `<lua>` block failed, with enough detail to point at the offending line.
+    #[error("Lua error: {0}")]
+    LuaBlockError(Box<LuaBlockError>),
+    #[error("Include error: {0}")]
+    IncludeError(String),
+    #[error("Parse error: {0}")]
+    ParseError(String),
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+    #[error("Syntax highlighting error: {0}")]
+    HighlightError(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl RenderError {
+    #[must_use]
+    pub fn status(&self) -> u16 {
+        match self {
+            RenderError::NotFound(_) => 404,
+            RenderError::Forbidden(_) => 403,
+            _ => 500,
+        }
+    }
+}
+
+/// A failed `<lua>` block and where it came from.
+#[derive(Debug, Default)]
+pub struct LuaBlockError {
+    pub message: String,
+    pub traceback: Option<String>,
+    /// The code of the block as it was executed.
+    pub code: String,
+    /// The page or component the block was read from.
+    pub source_file: Option<PathBuf>,
+    /// 1-based index of the block among the `<lua>` elements of `source_file`.
+    pub block: Option<usize>,
+    /// Line of `source_file` on which the block's code starts.
+    pub block_line: Option<usize>,
+    /// Line of `source_file` the error points at, when the Lua message names one.
+    pub line: Option<usize>,
+}
+
+impl LuaBlockError {
+    /// Splits the traceback off a Lua error and maps the line it reports onto the block's file.
+    #[must_use]
+    pub fn new(error: &mlua::Error, code: String) -> Self {
+        let full = error.to_string();
+        let (message, traceback) = match full.split_once("\nstack traceback:") {
+            Some((message, traceback)) => (message.to_string(), Some(format!("stack traceback:{traceback}"))),
+            None => (full, None),
+        };
+        Self {
+            message,
+            traceback,
+            code,
+            ..Self::default()
+        }
+    }
+
+    /// Sets where the block was read from and computes the absolute line of the error.
+    #[must_use]
+    pub fn located(mut self, source_file: PathBuf, block: usize, block_line: usize) -> Self {
+        self.line = chunk_line(&self.message, block_line).map(|line| block_line + line - 1);
+        self.source_file = Some(source_file);
+        self.block = Some(block);
+        self.block_line = Some(block_line);
+        self
+    }
+}
+
+impl Display for LuaBlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.source_file, self.line.or(self.block_line)) {
+            (Some(file), Some(line)) => write!(f, "{}:{line}: {}", file.display(), self.message),
+            _ => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Reads the chunk line out of a message such as `pages/index.html:42:3: boom`, from a chunk named after the line
+/// its block starts on.
+fn chunk_line(message: &str, block_line: usize) -> Option<usize> {
+    let marker = format!(":{block_line}:");
+    let start = message.find(&marker)? + marker.len();
+    let digits: String = message[start..].chars().take_while(char::is_ascii_digit).collect();
+    digits.parse().ok().filter(|&line| line > 0)
+}
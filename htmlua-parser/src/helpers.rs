@@ -1,33 +1,188 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, Read, Seek, SeekFrom},
-    path::PathBuf,
+    fs, io,
+    path::{Component, Path, PathBuf},
 };
 
-use anyhow::Result;
 use kuchikiki::NodeRef;
 use markup5ever::{LocalName, QualName, namespace_url, ns};
 use tendril::TendrilSink;
 
+use crate::{
+    config::Config,
+    error::{RenderError, Result},
+};
 
-pub fn read_doc_from_file(path: PathBuf) -> Result<NodeRef> {
-    let mut file = File::open(path)?;
-    let mut reader = BufReader::new(&mut file);
-
-    let mut first_line = String::new();
-    reader.read_line(&mut first_line)?;
-    let first_line_trimmed = first_line.trim();
+/// Attributes recording where a `<lua>` element was read from, for error reporting.
+pub const SOURCE_ATTRIBUTE: &str = "data-htmlua-source";
+pub const LINE_ATTRIBUTE: &str = "data-htmlua-line";
+pub const BLOCK_ATTRIBUTE: &str = "data-htmlua-block";
 
-    let is_whole_doc = first_line_trimmed.eq_ignore_ascii_case("<!DOCTYPE html>");
+/// Prefix of the attributes htmlua sets on `<lua>` elements for its own use.
+const INTERNAL_ATTRIBUTE_PREFIX: &str = "data-htmlua-";
 
-    file.seek(SeekFrom::Start(0))?;
-    let mut page_string = String::new();
-    file.read_to_string(&mut page_string)?;
+pub fn read_doc_from_file(path: &Path) -> io::Result<NodeRef> {
+    let page_string = fs::read_to_string(path)?;
+    let doc = parse_document(&page_string);
+    annotate_lua_sources(&doc, &page_string, path);
+    Ok(doc)
+}
 
+/// Parses a page or component: as a whole document when it starts with `<!DOCTYPE html>`, as a fragment otherwise.
+#[must_use]
+pub fn parse_document(source: &str) -> NodeRef {
+    let is_whole_doc = source
+        .lines()
+        .next()
+        .is_some_and(|line| line.trim().eq_ignore_ascii_case("<!DOCTYPE html>"));
     if is_whole_doc {
-        Ok(kuchikiki::parse_html().one(page_string))
+        kuchikiki::parse_html().one(source)
     } else {
         let ctx_name = QualName::new(None, ns!(), LocalName::from("div"));
-        Ok(kuchikiki::parse_fragment(ctx_name, Vec::new()).one(page_string))
+        kuchikiki::parse_fragment(ctx_name, Vec::new()).one(source)
+    }
+}
+
+/// Tags each `<lua>` element of a document parsed from `source` with its file, its 1-based index in the file and the
+/// line its code starts on, since the parsed tree has no positions.
+fn annotate_lua_sources(doc: &NodeRef, source: &str, path: &Path) {
+    let Ok(elements) = doc.select("lua") else {
+        return;
+    };
+    for (block, (element, line)) in elements.zip(lua_tag_lines(source)).enumerate() {
+        let mut attrs = element.attributes.borrow_mut();
+        attrs.insert(SOURCE_ATTRIBUTE, path.display().to_string());
+        attrs.insert(LINE_ATTRIBUTE, line.to_string());
+        attrs.insert(BLOCK_ATTRIBUTE, (block + 1).to_string());
+    }
+}
+
+/// Removes the internal attributes from the `<lua>` elements still in `doc`, e.g. when the page turned the lua stage
+/// off, so file paths and props don't end up in the response.
+pub fn strip_internal_attributes(doc: &NodeRef) {
+    let Ok(elements) = doc.select("lua") else {
+        return;
+    };
+    for element in elements {
+        element
+            .attributes
+            .borrow_mut()
+            .map
+            .retain(|name, _| !name.local.starts_with(INTERNAL_ATTRIBUTE_PREFIX));
+    }
+}
+
+/// Returns the line on which each `<lua>` opening tag in `source` ends, skipping comments.
+fn lua_tag_lines(source: &str) -> Vec<usize> {
+    let bytes = source.as_bytes();
+    let mut lines = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let skip_to = match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+                continue;
+            }
+            b'<' if source[i..].starts_with("<!--") => source[i..].find("-->").map_or(bytes.len(), |e| i + e + 3),
+            b'<' if is_lua_open_tag(&bytes[i + 1..]) => {
+                let end = source[i..].find('>').map_or(bytes.len(), |e| i + e);
+                lines.push(line + source[i..end].matches('\n').count());
+                end
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        line += source[i..skip_to].matches('\n').count();
+        i = skip_to;
+    }
+    lines
+}
+
+fn is_lua_open_tag(rest: &[u8]) -> bool {
+    rest.len() >= 3
+        && rest[..3].eq_ignore_ascii_case(b"lua")
+        && rest
+            .get(3)
+            .is_none_or(|c| c.is_ascii_whitespace() || *c == b'>' || *c == b'/')
+}
+
+/// Resolves `relative` against `root`, refusing anything that ends up outside `root` through `..`, an absolute path or
+/// a symlink. Returns the canonical path.
+pub fn resolve_within(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let mut depth = 0usize;
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(RenderError::Forbidden(relative.to_path_buf()));
+            }
+        }
+    }
+
+    let root = root
+        .canonicalize()
+        .map_err(|_| RenderError::NotFound(root.to_path_buf()))?;
+    let path = match root.join(relative).canonicalize() {
+        Ok(path) => path,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(RenderError::NotFound(relative.to_path_buf())),
+        Err(_) => return Err(RenderError::Forbidden(relative.to_path_buf())),
+    };
+    if path.starts_with(&root) {
+        Ok(path)
+    } else {
+        Err(RenderError::Forbidden(relative.to_path_buf()))
+    }
+}
+
+/// Names a file relative to the pages or components root it lives in, e.g. `pages/blog/index.html`.
+#[must_use]
+pub fn source_label(file: &Path, config: &Config) -> String {
+    [("pages", &config.paths.pages), ("components", &config.paths.components)]
+        .into_iter()
+        .find_map(|(name, root)| {
+            let root = root.canonicalize().ok()?;
+            let relative = file.strip_prefix(root).ok()?;
+            Some(Path::new(name).join(relative).display().to_string())
+        })
+        .unwrap_or_else(|| file.display().to_string())
+}
+
+/// Copies `node` and all of its descendants; cloning a `NodeRef` only copies the reference.
+#[must_use]
+pub fn deep_clone(node: &NodeRef) -> NodeRef {
+    let copy = NodeRef::new(node.data().clone());
+    for child in node.children() {
+        copy.append(deep_clone(&child));
+    }
+    copy
+}
+
+/// Parses `html` as a fragment in a `<div>` context and returns the parsed top-level nodes.
+#[must_use]
+pub fn parse_html_fragment(html: &str) -> Vec<NodeRef> {
+    let ctx_name = QualName::new(None, ns!(html), LocalName::from("div"));
+    let fragment = kuchikiki::parse_fragment(ctx_name, Vec::new()).one(html);
+    let root = fragment.first_child().unwrap_or(fragment);
+    root.children().collect()
+}
+
+#[must_use]
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
     }
+    escaped
 }
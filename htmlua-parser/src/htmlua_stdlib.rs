@@ -1,7 +1,6 @@
 use std::{
     cell::{LazyCell, RefCell},
     collections::HashMap,
-    fmt::Write,
     rc::Rc,
     str::FromStr,
     time::Duration,
@@ -15,8 +14,37 @@ use reqwest::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    helpers::escape_html,
+    request::Request,
+    response::{CookieOptions, Response as PageResponse},
+};
+
+
+/// Named registry key of the table mapping tag names to the handlers given to `htmlua.define_tag`.
+pub const LUA_TAGS_KEY: &str = "htmlua.tags";
+
+/// Output captured from a `<lua>` block, kept as HTML so it can be spliced back into the document.
+#[derive(Debug, Default)]
+pub struct LuaOutput {
+    pub html: String,
+    /// Set for `<lua mode="html">` blocks, where `htmlua.print` writes markup unescaped.
+    pub raw: bool,
+}
+
+impl LuaOutput {
+    fn write_text(&mut self, text: &str) {
+        if self.raw {
+            self.html.push_str(text);
+        } else {
+            self.html.push_str(&escape_html(text));
+        }
+    }
+}
 
-pub fn create_htmlua_stdlib(l: &Lua, stdout: &Rc<RefCell<String>>) -> mlua::Result<Table> {
+pub fn create_htmlua_stdlib(
+    l: &Lua, stdout: &Rc<RefCell<LuaOutput>>, request: &Request, response: &Rc<RefCell<PageResponse>>,
+) -> mlua::Result<Table> {
     let t = l.create_table()?;
 
     // This cannot be the best way to do this
@@ -26,7 +54,9 @@ pub fn create_htmlua_stdlib(l: &Lua, stdout: &Rc<RefCell<String>>) -> mlua::Resu
         "println",
         l.create_function(move |_, text: String| {
             let mut stdout_ref = stdout_println.borrow_mut();
-            writeln!(stdout_ref, "{text}").map_err(mlua::Error::external)
+            stdout_ref.write_text(&text);
+            stdout_ref.write_text("\n");
+            Ok(())
         })?,
     )?;
 
@@ -34,12 +64,143 @@ pub fn create_htmlua_stdlib(l: &Lua, stdout: &Rc<RefCell<String>>) -> mlua::Resu
     t.set(
         "print",
         l.create_function(move |_, text: String| {
-            let mut stdout_ref = stdout_print.borrow_mut();
-            write!(stdout_ref, "{text}").map_err(mlua::Error::external)
+            stdout_print.borrow_mut().write_text(&text);
+            Ok(())
         })?,
     )?;
 
+    let stdout_print_html = stdout.clone();
+    let print_html = l.create_function(move |_, html: String| {
+        stdout_print_html.borrow_mut().html.push_str(&html);
+        Ok(())
+    })?;
+    t.set("print_html", print_html.clone())?;
+    t.set("html", print_html)?;
+
     t.set("http", create_http_lib(l)?)?;
+    t.set("request", request.to_lua_table(l)?)?;
+    t.set("response", create_response_lib(l, response)?)?;
+    // Reachable from every `<lua>` scope, for values components mean to share.
+    t.set("shared", l.create_table()?)?;
+
+    l.set_named_registry_value(LUA_TAGS_KEY, l.create_table()?)?;
+    t.set(
+        "define_tag",
+        l.create_function(|l, (name, handler): (String, LuaFunction)| {
+            let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid {
+                return Err(Error::RuntimeError(format!(
+                    "invalid tag name '{name}', expected lowercase letters, digits and '-'"
+                )));
+            }
+            l.named_registry_value::<Table>(LUA_TAGS_KEY)?.set(name, handler)
+        })?,
+    )?;
+    Ok(t)
+}
+
+fn create_response_lib(l: &Lua, response: &Rc<RefCell<PageResponse>>) -> mlua::Result<Table> {
+    let t = l.create_table()?;
+
+    let res = response.clone();
+    t.set(
+        "set_status",
+        l.create_function(move |_, status: u16| {
+            if !(100..=999).contains(&status) {
+                return Err(Error::RuntimeError(format!("invalid status code {status}")));
+            }
+            res.borrow_mut().status = status;
+            Ok(())
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "set_header",
+        l.create_function(move |_, (name, value): (String, String)| {
+            res.borrow_mut()
+                .set_header(&name, &value)
+                .map_err(|e| Error::RuntimeError(e.to_string()))
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "add_header",
+        l.create_function(move |_, (name, value): (String, String)| {
+            res.borrow_mut()
+                .add_header(&name, &value)
+                .map_err(|e| Error::RuntimeError(e.to_string()))
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "set_content_type",
+        l.create_function(move |_, content_type: String| {
+            res.borrow_mut()
+                .set_content_type(&content_type)
+                .map_err(|e| Error::RuntimeError(e.to_string()))
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "set_cookie",
+        l.create_function(move |_, (name, value, opts): (String, String, Option<Table>)| {
+            let options = match opts {
+                Some(o) => CookieOptions {
+                    path: o.get("path")?,
+                    domain: o.get("domain")?,
+                    max_age: o.get("max_age")?,
+                    expires: o.get("expires")?,
+                    secure: o.get::<Option<bool>>("secure")?.unwrap_or_default(),
+                    http_only: o.get::<Option<bool>>("http_only")?.unwrap_or_default(),
+                    same_site: o.get("same_site")?,
+                },
+                None => CookieOptions::default(),
+            };
+            res.borrow_mut()
+                .set_cookie(&name, &value, &options)
+                .map_err(|e| Error::RuntimeError(e.to_string()))
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "redirect",
+        l.create_function(move |_, (url, status): (String, Option<u16>)| {
+            res.borrow_mut()
+                .redirect(&url, status.unwrap_or(302))
+                .map_err(|e| Error::RuntimeError(e.to_string()))
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "set_body",
+        l.create_function(move |_, body: String| {
+            res.borrow_mut().body = body;
+            Ok(())
+        })?,
+    )?;
+
+    let res = response.clone();
+    t.set(
+        "json",
+        l.create_function(move |_, value: LuaValue| {
+            let body = serde_json::to_string(&value).map_err(|e| Error::RuntimeError(e.to_string()))?;
+            let mut res = res.borrow_mut();
+            res.set_content_type("application/json")
+                .map_err(|e| Error::RuntimeError(e.to_string()))?;
+            res.body = body;
+            Ok(())
+        })?,
+    )?;
+
     Ok(t)
 }
 
@@ -1,5 +1,13 @@
 pub mod config;
+pub mod dev_overlay;
+pub mod error;
 pub mod helpers;
 pub mod htmlua_stdlib;
+pub mod lua_modules;
+pub mod pipeline;
 pub mod render;
+pub mod renderer;
+pub mod request;
+pub mod response;
+pub mod sandbox;
 pub mod serve;
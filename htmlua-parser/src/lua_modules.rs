@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use mlua::{Lua, Table, Value};
+
+/// Makes `require("blog.util")` resolve to `<root>/blog/util.lua` (or `<root>/blog/util/init.lua`), and only
+/// there: the default `package.path`/`package.cpath` searchers are dropped whether or not the sandbox is on.
+/// Modules are cached in `package.loaded`, so each loads once per Lua state.
+pub fn install_module_searcher(lua: &Lua, root: &Path) -> mlua::Result<()> {
+    let package: Table = lua.globals().get("package")?;
+    package.set("path", "")?;
+    package.set("cpath", "")?;
+    let searchers: Table = package.get("searchers")?;
+    // Position 1 is `package.preload`, which should keep priority; the rest search the filesystem.
+    for position in (2..=searchers.raw_len()).rev() {
+        searchers.raw_remove(position)?;
+    }
+    let root = root.to_path_buf();
+    let searcher = lua.create_function(move |lua, name: String| {
+        let path = match resolve_module(&root, &name) {
+            Ok(path) => path,
+            Err(reason) => return Ok((Value::String(lua.create_string(format!("\n\t{reason}"))?), Value::Nil)),
+        };
+        let source = fs::read_to_string(&path).map_err(mlua::Error::external)?;
+        let loader = lua
+            .load(source)
+            .set_name(format!("@{}", path.display()))
+            .into_function()?;
+        let file_name = lua.create_string(path.to_string_lossy().as_bytes())?;
+        Ok((Value::Function(loader), Value::String(file_name)))
+    })?;
+    searchers.raw_insert(2, searcher)
+}
+
+fn resolve_module(root: &Path, name: &str) -> Result<PathBuf, String> {
+    let valid_segment = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !name.split('.').all(valid_segment) {
+        return Err(format!("invalid module name '{name}'"));
+    }
+    let root = root
+        .canonicalize()
+        .map_err(|e| format!("lua_modules directory {}: {e}", root.display()))?;
+    let relative: PathBuf = name.split('.').collect();
+    let candidates = [relative.with_extension("lua"), relative.join("init.lua")];
+    for candidate in candidates {
+        let Ok(path) = root.join(&candidate).canonicalize() else {
+            continue;
+        };
+        // Symlinks inside the directory must not lead outside it.
+        if path.starts_with(&root) && path.is_file() {
+            return Ok(path);
+        }
+    }
+    Err(format!("no file '{}' in {}", relative.with_extension("lua").display(), root.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::LuaConfig, sandbox::new_lua};
+
+    fn modules_root() -> PathBuf {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/lua_modules");
+        p
+    }
+
+    #[test]
+    fn require_module() {
+        let lua = new_lua(&LuaConfig::default()).unwrap();
+        install_module_searcher(&lua, &modules_root()).unwrap();
+        let code = r#"
+            local util = require("blog.util")
+            local again = require("blog.util")
+            return util.slug("Hello World") .. " " .. tostring(util == again) .. " " .. util.loads
+        "#;
+        assert_eq!(lua.load(code).eval::<String>().unwrap(), "hello-world true 1");
+        assert_eq!(lua.load(r#"return require("blog").name"#).eval::<String>().unwrap(), "blog");
+    }
+
+    #[test]
+    fn require_outside_root() {
+        let lua = new_lua(&LuaConfig::default()).unwrap();
+        install_module_searcher(&lua, &modules_root()).unwrap();
+        assert!(lua.load(r#"require("..components.comp1")"#).exec().is_err());
+        assert!(lua.load(r#"require("../components/comp1")"#).exec().is_err());
+        assert!(lua.load(r#"require("missing")"#).exec().is_err());
+    }
+
+    #[test]
+    fn require_ignores_package_path() {
+        // `blog.util` exists under this path, but not under the `blog` directory used as the root below.
+        let path = format!("{}/?.lua", modules_root().display());
+        for sandbox in [true, false] {
+            let lua = new_lua(&LuaConfig {
+                sandbox,
+                ..LuaConfig::default()
+            })
+            .unwrap();
+            install_module_searcher(&lua, &modules_root().join("blog")).unwrap();
+            assert_eq!(
+                lua.load("return package.path .. package.cpath")
+                    .eval::<String>()
+                    .unwrap(),
+                ""
+            );
+            lua.load(format!("package.path = {path:?}")).exec().unwrap();
+            assert!(lua.load(r#"require("util")"#).exec().is_ok());
+            assert!(lua.load(r#"require("blog.util")"#).exec().is_err(), "sandbox = {sandbox}");
+        }
+    }
+}
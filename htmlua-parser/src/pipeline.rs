@@ -0,0 +1,286 @@
+use std::{path::PathBuf, sync::Arc};
+
+use kuchikiki::NodeRef;
+
+use crate::{
+    config::Config,
+    error::{RenderError, Result},
+    helpers::strip_internal_attributes,
+    render::{
+        RenderContext, execute_lua, expand_lua_tags, expand_template, generate_footnotes, highlight_document,
+        inside_lua, process_markdown,
+    },
+};
+
+/// Lets a page turn off stages, e.g. `<meta name="htmlua-pipeline" content="-markdown, -footnotes">`.
+const PIPELINE_META: &str = r#"meta[name="htmlua-pipeline"]"#;
+
+/// The stages `templates.pipeline` can name.
+const BUILTIN_STAGES: [&str; 6] = ["include", "lua", "markdown", "highlight", "footnotes", "tags"];
+
+/// One stage of the render pipeline.
+pub trait Processor: Send + Sync {
+    /// The name the stage goes by in `templates.pipeline` and the page's `htmlua-pipeline` meta tag.
+    fn name(&self) -> &str;
+
+    fn process(&self, document: NodeRef, ctx: &RenderContext) -> Result<NodeRef>;
+}
+
+/// A custom element such as `<youtube id="...">`, registered on a [`Renderer`](crate::renderer::Renderer) and run
+/// by the `tags` stage.
+pub trait TagProcessor: Send + Sync {
+    /// The element name this processor handles.
+    fn tag(&self) -> &str;
+
+    /// Renders one element. `node` is still in the document, so the processor replaces or detaches it as needed.
+    fn process(&self, node: &NodeRef, ctx: &RenderContext) -> Result<()>;
+}
+
+/// The tag processors of a renderer, shared with each pipeline it builds.
+pub type TagRegistry = Arc<Vec<Box<dyn TagProcessor>>>;
+
+/// Runs the processors of a page in order.
+#[derive(Default)]
+pub struct RenderPipeline {
+    processors: Vec<Box<dyn Processor>>,
+}
+
+impl RenderPipeline {
+    /// Builds the pipeline named by `templates.pipeline`, with `tags` for its `tags` stage.
+    pub fn from_config(config: &Config, tags: &TagRegistry) -> Result<Self> {
+        let mut pipeline = Self::default();
+        for name in &config.templates.pipeline {
+            pipeline = pipeline.with(builtin_processor(name, config, tags)?);
+        }
+        Ok(pipeline)
+    }
+
+    #[must_use]
+    pub fn with(mut self, processor: Box<dyn Processor>) -> Self {
+        self.processors.push(processor);
+        self
+    }
+
+    /// Runs every stage the page hasn't disabled in its `htmlua-pipeline` meta tag.
+    pub fn run(&self, document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> {
+        let disabled = self.take_disabled_stages(&document)?;
+        let document = self
+            .processors
+            .iter()
+            .filter(|p| !disabled.iter().any(|name| name == p.name()))
+            .try_fold(document, |document, processor| processor.process(document, ctx))?;
+        strip_internal_attributes(&document);
+        Ok(document)
+    }
+
+    /// Reads and removes the page's `htmlua-pipeline` meta tags. Turning off a known stage this pipeline doesn't have
+    /// is allowed, so pages keep working when a site's pipeline changes.
+    fn take_disabled_stages(&self, document: &NodeRef) -> Result<Vec<String>> {
+        let metas: Vec<_> = document
+            .select(PIPELINE_META)
+            .map_err(|()| RenderError::ParseError("Unable to find pipeline meta tags".to_string()))?
+            .collect();
+        let mut disabled = Vec::new();
+        for meta in metas {
+            let content = meta.attributes.borrow().get("content").unwrap_or_default().to_string();
+            for entry in content.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                match entry.strip_prefix('-') {
+                    Some(name) if self.processors.iter().any(|p| p.name() == name) => disabled.push(name.to_string()),
+                    Some(name) if BUILTIN_STAGES.contains(&name) => {}
+                    _ => {
+                        return Err(RenderError::ParseError(format!(
+                            "Invalid htmlua-pipeline entry '{entry}', expected '-stage' for a pipeline stage"
+                        )));
+                    }
+                }
+            }
+            meta.as_node().detach();
+        }
+        Ok(disabled)
+    }
+}
+
+fn builtin_processor(name: &str, config: &Config, tags: &TagRegistry) -> Result<Box<dyn Processor>> {
+    match name {
+        "include" => Ok(Box::new(IncludeProcessor {
+            components: config.paths.components.clone(),
+        })),
+        "lua" => Ok(Box::new(LuaProcessor)),
+        "markdown" => Ok(Box::new(MarkdownProcessor)),
+        "highlight" => Ok(Box::new(HighlightProcessor)),
+        "footnotes" => Ok(Box::new(FootnotesProcessor)),
+        "tags" => Ok(Box::new(TagsProcessor { tags: tags.clone() })),
+        _ => Err(RenderError::ConfigError(format!("Unknown pipeline stage '{name}'"))),
+    }
+}
+
+/// Expands `<include>` elements from the components directory.
+pub struct IncludeProcessor {
+    pub components: PathBuf,
+}
+
+impl Processor for IncludeProcessor {
+    fn name(&self) -> &'static str { "include" }
+
+    fn process(&self, document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> {
+        expand_template(document, &self.components, None, ctx)
+    }
+}
+
+/// Runs `<lua>` blocks, interpolation and control flow, in document order.
+pub struct LuaProcessor;
+
+impl Processor for LuaProcessor {
+    fn name(&self) -> &'static str { "lua" }
+
+    fn process(&self, document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> { execute_lua(document, ctx) }
+}
+
+pub struct MarkdownProcessor;
+
+impl Processor for MarkdownProcessor {
+    fn name(&self) -> &'static str { "markdown" }
+
+    fn process(&self, document: NodeRef, _: &RenderContext) -> Result<NodeRef> { process_markdown(document) }
+}
+
+pub struct HighlightProcessor;
+
+impl Processor for HighlightProcessor {
+    fn name(&self) -> &'static str { "highlight" }
+
+    fn process(&self, document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> {
+        highlight_document(document, &ctx.renderer)
+    }
+}
+
+pub struct FootnotesProcessor;
+
+impl Processor for FootnotesProcessor {
+    fn name(&self) -> &'static str { "footnotes" }
+
+    fn process(&self, document: NodeRef, _: &RenderContext) -> Result<NodeRef> { generate_footnotes(document) }
+}
+
+/// Runs each registered tag processor over its elements in document order, then the tags defined from Lua.
+pub struct TagsProcessor {
+    pub tags: TagRegistry,
+}
+
+impl Processor for TagsProcessor {
+    fn name(&self) -> &'static str { "tags" }
+
+    fn process(&self, document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> {
+        for processor in self.tags.iter() {
+            let elements: Vec<_> = document
+                .select(processor.tag())
+                .map_err(|()| RenderError::ParseError(format!("Invalid custom tag name '{}'", processor.tag())))?
+                .collect();
+            for element in elements {
+                if !inside_lua(element.as_node()) {
+                    processor.process(element.as_node(), ctx)?;
+                }
+            }
+        }
+        expand_lua_tags(document, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kuchikiki::traits::TendrilSink;
+
+    use super::*;
+    use crate::{helpers::parse_html_fragment, renderer::Renderer, request::Request};
+
+    fn test_ctx() -> RenderContext { RenderContext::new(Request::default(), Renderer::new(Config::default()).unwrap()) }
+
+    fn pipeline(stages: &[&str]) -> RenderPipeline {
+        let mut config = Config::default();
+        config.templates.pipeline = stages.iter().map(ToString::to_string).collect();
+        RenderPipeline::from_config(&config, &TagRegistry::default()).unwrap()
+    }
+
+    const LUA_MARKDOWN: &str = r#"<div><lua mode="html">htmlua.print("<markdown># Title</markdown>")</lua></div>"#;
+
+    #[test]
+    fn stage_order() {
+        let document = kuchikiki::parse_html().one(LUA_MARKDOWN);
+        let d = pipeline(&["lua", "markdown"]).run(document, &test_ctx()).unwrap();
+        assert_eq!(d.select_first("h1").unwrap().text_contents(), "Title");
+
+        let document = kuchikiki::parse_html().one(LUA_MARKDOWN);
+        let d = pipeline(&["markdown", "lua"]).run(document, &test_ctx()).unwrap();
+        assert!(d.select_first("h1").is_err());
+        assert!(d.select_first("markdown").is_ok());
+    }
+
+    #[test]
+    fn page_disables_stage() {
+        let page = format!(r#"<meta name="htmlua-pipeline" content="-markdown">{LUA_MARKDOWN}"#);
+        let document = kuchikiki::parse_html().one(page);
+        let d = pipeline(&["lua", "markdown"]).run(document, &test_ctx()).unwrap();
+        assert!(d.select_first("h1").is_err());
+        assert!(d.select_first("meta").is_err());
+
+        let page = r#"<meta name="htmlua-pipeline" content="-typo"><p>x</p>"#;
+        let document = kuchikiki::parse_html().one(page);
+        assert!(pipeline(&["lua"]).run(document, &test_ctx()).is_err());
+
+        let page = r#"<meta name="htmlua-pipeline" content="-footnotes"><p>x</p>"#;
+        let document = kuchikiki::parse_html().one(page);
+        assert!(pipeline(&["lua"]).run(document, &test_ctx()).is_ok());
+    }
+
+    #[test]
+    fn unknown_stage() {
+        let mut config = Config::default();
+        config.templates.pipeline = vec!["lua".to_string(), "minify".to_string()];
+        assert!(matches!(
+            RenderPipeline::from_config(&config, &TagRegistry::default()),
+            Err(RenderError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn footnotes_stage() {
+        let page = "<div><p>Text<footnote>Note</footnote></p><footnotecontainer></footnotecontainer></div>";
+        let document = kuchikiki::parse_html().one(page);
+        let d = pipeline(&["footnotes"]).run(document, &test_ctx()).unwrap();
+        assert_eq!(d.select_first("#ft-sup-1").unwrap().text_contents(), "1");
+    }
+
+    struct Youtube;
+
+    impl TagProcessor for Youtube {
+        fn tag(&self) -> &'static str { "youtube" }
+
+        fn process(&self, node: &NodeRef, _: &RenderContext) -> Result<()> {
+            let id = node
+                .as_element()
+                .and_then(|e| e.attributes.borrow().get("id").map(str::to_string));
+            let html = format!(r#"<iframe src="https://www.youtube.com/embed/{}"></iframe>"#, id.unwrap_or_default());
+            for child in parse_html_fragment(&html) {
+                node.insert_before(child);
+            }
+            node.detach();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_tag() {
+        let mut config = Config::default();
+        config.templates.pipeline = vec!["tags".to_string()];
+        let tags: TagRegistry = Arc::new(vec![Box::new(Youtube)]);
+        let page = r#"<div><youtube id="abc"></youtube><lua>x = "<youtube></youtube>"</lua></div>"#;
+        let document = kuchikiki::parse_html().one(page);
+        let d = RenderPipeline::from_config(&config, &tags)
+            .unwrap()
+            .run(document, &test_ctx())
+            .unwrap();
+        let iframe = d.select_first("iframe").unwrap();
+        assert_eq!(iframe.attributes.borrow().get("src"), Some("https://www.youtube.com/embed/abc"));
+        assert!(d.select_first("lua youtube").is_ok());
+    }
+}
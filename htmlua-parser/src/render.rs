@@ -1,98 +1,707 @@
 use std::{
-    cell::{LazyCell, RefCell},
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, HashMap},
     fmt::Write,
-    path::PathBuf,
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
     rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
-use anyhow::{Result, anyhow};
-use kuchikiki::{NodeRef, traits::TendrilSink};
+use kuchikiki::{Attributes, ElementData, Node, NodeRef, traits::TendrilSink};
 use markup5ever::{LocalName, Namespace, QualName};
-use mlua::Lua;
+use mlua::{Lua, Table, Value};
 use pulldown_cmark::{Options, Parser, html};
 use syntect::{
     easy::HighlightLines,
-    highlighting::{Style, ThemeSet},
-    html::{IncludeBackground, styled_line_to_highlighted_html},
-    parsing::SyntaxSet,
+    highlighting::{Style, Theme, ThemeSet},
+    html::{ClassStyle, IncludeBackground, line_tokens_to_classed_spans, styled_line_to_highlighted_html},
+    parsing::{ParseState, ScopeStack, SyntaxSet},
     util::LinesWithEndings,
 };
 
-use crate::{helpers::read_doc_from_file, htmlua_stdlib::create_htmlua_stdlib, serve::get_config};
+use crate::{
+    config::Config,
+    error::{LuaBlockError, RenderError, Result},
+    helpers::{
+        BLOCK_ATTRIBUTE, LINE_ATTRIBUTE, SOURCE_ATTRIBUTE, deep_clone, escape_html, parse_html_fragment,
+        read_doc_from_file, resolve_within, source_label,
+    },
+    htmlua_stdlib::{LUA_TAGS_KEY, LuaOutput, create_htmlua_stdlib},
+    lua_modules::install_module_searcher,
+    renderer::Renderer,
+    request::Request,
+    response::Response,
+    sandbox::new_lua,
+};
+
+
+/// Carries include attributes from `expand_template` to the component's `<lua>` blocks as `htmlua.props`.
+const PROPS_ATTRIBUTE: &str = "data-htmlua-props";
+
+/// Marks the `<lua>` blocks of one include of a component, so `scope="component"` blocks of that include share an
+/// environment.
+const INSTANCE_ATTRIBUTE: &str = "data-htmlua-instance";
+
+/// Source of unique include ids for `INSTANCE_ATTRIBUTE`.
+static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(1);
+
+/// A `{{` that is text rather than the start of a placeholder.
+const ESCAPED_PLACEHOLDER: &str = "\\{{";
+
+/// `lua:href="expr"` sets `href` to the value of `expr`.
+const LUA_ATTRIBUTE_PREFIX: &str = "lua:";
+
+/// Prefix of the scope classes on highlighted code when `syntax_highlighting.css_classes` is on.
+pub const HIGHLIGHT_CLASS_PREFIX: &str = "hl-";
+
+pub const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: HIGHLIGHT_CLASS_PREFIX,
+};
+
+/// Per-render state shared between the render passes and the Lua runtime.
+#[derive(Debug)]
+pub struct RenderContext {
+    pub request: Request,
+    pub response: Rc<RefCell<Response>>,
+    /// The renderer whose config and assets this render uses.
+    pub renderer: Renderer,
+    lua: OnceCell<LuaRuntime>,
+    /// Nodes and attributes produced by Lua or filled from include props, which `execute_lua` leaves alone.
+    output: RefCell<HashMap<*const Node, Output>>,
+}
+
+/// Marks a node that came from Lua output or an include prop rather than from page markup.
+#[derive(Debug)]
+struct Output {
+    /// Keeps the node alive, so its address isn't reused by a node that is markup.
+    #[allow(dead_code)]
+    node: NodeRef,
+    /// The attributes filled from a prop, or `None` when the whole node is output.
+    attributes: Option<Vec<String>>,
+}
 
+/// The page's Lua state. Created on first use so pages without Lua never pay for it, then shared by every pass
+/// that evaluates Lua during the render.
+#[derive(Debug)]
+pub struct LuaRuntime {
+    pub lua: Lua,
+    pub output: Rc<RefCell<LuaOutput>>,
+    /// `_ENV` tables of `scope="component"` blocks, by include instance.
+    scopes: RefCell<HashMap<String, Table>>,
+}
 
-fn build_lua_with_stdout(stdout: &Rc<RefCell<String>>) -> Result<Lua> {
-    let lua = Lua::new();
+impl RenderContext {
+    #[must_use]
+    pub fn new(request: Request, renderer: Renderer) -> Self {
+        Self {
+            request,
+            response: Rc::default(),
+            renderer,
+            lua: OnceCell::new(),
+            output: RefCell::default(),
+        }
+    }
+
+    /// The Lua state, if a pass has needed it so far.
+    #[must_use]
+    pub fn started_lua(&self) -> Option<&LuaRuntime> { self.lua.get() }
+
+    pub fn lua(&self) -> Result<&LuaRuntime> {
+        if let Some(runtime) = self.lua.get() {
+            return Ok(runtime);
+        }
+        let output = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&output, self)?;
+        Ok(self.lua.get_or_init(|| LuaRuntime {
+            lua,
+            output,
+            scopes: RefCell::default(),
+        }))
+    }
+
+    /// Marks `nodes` and everything in them as output, so `{{ }}`, `lua:` attributes and control-flow elements in
+    /// them are not evaluated.
+    fn mark_output(&self, nodes: &[NodeRef]) {
+        let mut output = self.output.borrow_mut();
+        for node in nodes.iter().flat_map(NodeRef::inclusive_descendants) {
+            output.insert(Rc::as_ptr(&node.0), Output { node, attributes: None });
+        }
+    }
+
+    /// Marks the attribute `name` of `node` as filled from a prop, so `{{ }}` in its value is not evaluated.
+    fn mark_output_attribute(&self, node: &NodeRef, name: &str) {
+        let mut output = self.output.borrow_mut();
+        let entry = output.entry(Rc::as_ptr(&node.0)).or_insert_with(|| Output {
+            node: node.clone(),
+            attributes: Some(Vec::new()),
+        });
+        if let Some(attributes) = &mut entry.attributes {
+            attributes.push(name.to_string());
+        }
+    }
+
+    /// Whether all of `node` is output.
+    fn is_output(&self, node: &NodeRef) -> bool {
+        self.output
+            .borrow()
+            .get(&Rc::as_ptr(&node.0))
+            .is_some_and(|o| o.attributes.is_none())
+    }
+
+    /// The attributes of `node` marked by `mark_output_attribute`.
+    fn output_attributes(&self, node: &NodeRef) -> Vec<String> {
+        self.output
+            .borrow()
+            .get(&Rc::as_ptr(&node.0))
+            .and_then(|o| o.attributes.clone())
+            .unwrap_or_default()
+    }
+
+    /// Carries the marks of `original` and its descendants over to their counterparts in `copy`, a `deep_clone`.
+    fn copy_output_marks(&self, original: &NodeRef, copy: &NodeRef) {
+        let mut output = self.output.borrow_mut();
+        for (from, to) in original.inclusive_descendants().zip(copy.inclusive_descendants()) {
+            if let Some(attributes) = output.get(&Rc::as_ptr(&from.0)).map(|o| o.attributes.clone()) {
+                output.insert(Rc::as_ptr(&to.0), Output { node: to, attributes });
+            }
+        }
+    }
+}
+
+fn build_lua_with_stdout(stdout: &Rc<RefCell<LuaOutput>>, ctx: &RenderContext) -> Result<Lua> {
+    let config = ctx.renderer.config();
+    let lua = new_lua(&config.lua).map_err(|e| RenderError::LuaError(format!("Failed to create Lua state: {e}")))?;
+    install_module_searcher(&lua, &config.paths.lua_modules)
+        .map_err(|e| RenderError::LuaError(format!("Failed to install Lua module searcher: {e}")))?;
     let globals = lua.globals();
 
-    let htmlua_table = create_htmlua_stdlib(&lua, stdout).map_err(|e| anyhow!("Failed to create Lua stdlib: {}", e))?;
+    let htmlua_table = create_htmlua_stdlib(&lua, stdout, &ctx.request, &ctx.response)
+        .map_err(|e| RenderError::LuaError(format!("Failed to create Lua stdlib: {e}")))?;
 
     globals
         .set("htmlua", htmlua_table)
-        .map_err(|e| anyhow!("Failed to set global: {}", e))?;
+        .map_err(|e| RenderError::LuaError(format!("Failed to set global: {e}")))?;
+
+    if let Some(script) = &config.lua.startup_script {
+        let source = fs::read_to_string(script)?;
+        lua.load(source)
+            .set_name(format!("@{}", script.display()))
+            .exec()
+            .map_err(|e| RenderError::LuaError(format!("Failed to run startup script {}: {e}", script.display())))?;
+    }
 
     Ok(lua)
 }
 
-pub fn execute_lua(document: NodeRef) -> Result<NodeRef> {
-    let stdout: Rc<RefCell<String>> = Rc::new(RefCell::new(String::new()));
-    let lua = LazyCell::new(|| build_lua_with_stdout(&stdout).unwrap_or_default());
-    let lua_elements: Vec<_> = match document.select("lua") {
+/// Renders the Lua parts of a document in a single pass in document order, so each part sees what the blocks before
+/// it did:
+///
+/// - Each `<lua>` block runs and is replaced with its output. Blocks share the page globals unless marked
+///   `scope="local"`, which gives the block its own, or `scope="component"`, which shares them between the blocks of
+///   one include; both still read through to the page globals.
+/// - `{{ expr }}` in text and attribute values is replaced with the value of `expr`; write `\{{` for a literal `{{`.
+/// - `lua:name="expr"` sets the attribute `name`; `nil` or `false` leaves it out, `true` makes it empty.
+/// - `<if cond="expr">`, `<else>` and `<for each="item in expr">` (or `each="key, value in expr"`) are expanded, and
+///   the blocks and placeholders in them run once per expansion.
+///
+/// Content of `<lua>`, `<script>`, `<style>`, `<pre>` and `<code>` is left alone, as is anything Lua printed or an
+/// include prop filled in, so request data can't be evaluated as Lua.
+pub fn execute_lua(document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> {
+    render_nodes(document.children().collect(), ctx, None)?;
+    Ok(document)
+}
+
+/// Runs the `<lua phase="pre">` blocks of a page or component before its includes are expanded. Like other blocks,
+/// they escape `htmlua.print` unless marked `mode="html"`; `<include>` tags they emit with `htmlua.print_html` are
+/// picked up by `expand_template`.
+fn execute_pre_lua(document: &NodeRef, ctx: &RenderContext) -> Result<()> {
+    let lua_elements: Vec<_> = match document.select(r#"lua[phase="pre"]"#) {
         Ok(e) => e.collect(),
-        Err(()) => return Err(anyhow!("Unable to find Lua")),
+        Err(()) => return Err(RenderError::ParseError("Unable to find Lua".to_string())),
     };
-
+    if lua_elements.is_empty() {
+        return Ok(());
+    }
     for node in lua_elements {
-        if let Some(text_node) = node.as_node().first_child() {
-            if let Some(lua_code) = text_node.as_text() {
-                stdout.borrow_mut().clear();
-                lua.load(lua_code.borrow().as_str())
-                    .exec()
-                    .map_err(|e| anyhow!("Failed to execute Lua: {}", e))?;
-                node.as_node()
-                    .insert_before(NodeRef::new_text(stdout.borrow().as_str()));
-                node.as_node().detach();
+        if !ctx.is_output(node.as_node()) {
+            let raw = node.attributes.borrow().get("mode") == Some("html");
+            run_lua_block(node.as_node(), ctx, raw, None)?;
+        }
+    }
+    Ok(())
+}
+
+/// Executes one `<lua>` element and replaces it with what it printed, parsed as HTML. With `raw` unset, text from
+/// `htmlua.print` is escaped first. `env` is the environment of the enclosing `<for>`, if any.
+fn run_lua_block(node: &NodeRef, ctx: &RenderContext, raw: bool, env: Option<&Table>) -> Result<()> {
+    let LuaRuntime {
+        lua,
+        output: stdout,
+        scopes,
+    } = ctx.lua()?;
+    let Some(element) = node.as_element() else {
+        return Ok(());
+    };
+    let lua_code = lua_source(node);
+    if lua_code.trim().is_empty() {
+        return Ok(());
+    }
+    {
+        let mut out = stdout.borrow_mut();
+        out.html.clear();
+        out.raw = raw;
+    }
+    let props: BTreeMap<String, String> = match element.attributes.borrow().get(PROPS_ATTRIBUTE) {
+        Some(json) => serde_json::from_str(json).map_err(|e| RenderError::ParseError(format!("Invalid props: {e}")))?,
+        None => BTreeMap::new(),
+    };
+    lua.globals()
+        .get::<Table>("htmlua")
+        .and_then(|htmlua| htmlua.set("props", lua.create_table_from(props)?))
+        .map_err(|e| RenderError::LuaError(format!("Failed to set props: {e}")))?;
+    let mut chunk = lua.load(&lua_code);
+    if let Some(name) = chunk_name(node, ctx.renderer.config()) {
+        chunk = chunk.set_name(name);
+    }
+    let env = {
+        let attrs = element.attributes.borrow();
+        match (attrs.get("scope"), attrs.get(INSTANCE_ATTRIBUTE)) {
+            (None | Some("global"), _) | (Some("component"), None) => env.cloned(),
+            (Some("local"), _) => Some(scoped_env(lua, env)?),
+            (Some("component"), Some(instance)) => {
+                let mut scopes = scopes.borrow_mut();
+                if !scopes.contains_key(instance) {
+                    scopes.insert(instance.to_string(), scoped_env(lua, None)?);
+                }
+                let component = scopes.get(instance).cloned();
+                match (component, env) {
+                    (Some(component), Some(loop_env)) => Some(loop_scoped_env(lua, component, loop_env.clone())?),
+                    (component, _) => component,
+                }
             }
+            (Some(scope), _) => return Err(RenderError::ParseError(format!("Unknown Lua scope '{scope}'"))),
         }
+    };
+    if let Some(env) = env {
+        chunk = chunk.set_environment(env);
+    }
+    if let Err(e) = chunk.exec() {
+        return Err(lua_block_error(node, &e, lua_code));
     }
+    let output = parse_html_fragment(&stdout.borrow().html);
+    for child in &output {
+        node.insert_before(child.clone());
+    }
+    node.detach();
+    ctx.mark_output(&output);
+    Ok(())
+}
 
+/// Replaces elements named in `htmlua.define_tag` with the HTML their handler returns for `(attrs, inner_html)`.
+/// Tags in that output are expanded too, as deep as `templates.max_include_depth`. Pages only get a Lua state for
+/// this when they already have one or a startup script could have defined tags.
+pub fn expand_lua_tags(document: NodeRef, ctx: &RenderContext) -> Result<NodeRef> {
+    let runtime = match ctx.started_lua() {
+        Some(runtime) => runtime,
+        None if ctx.renderer.config().lua.startup_script.is_some() => ctx.lua()?,
+        None => return Ok(document),
+    };
+    let tags: Table = runtime
+        .lua
+        .named_registry_value(LUA_TAGS_KEY)
+        .map_err(|e| RenderError::LuaError(format!("Failed to get Lua tags: {e}")))?;
+    if tags.is_empty() {
+        return Ok(document);
+    }
+    let max_depth = ctx.renderer.config().templates.max_include_depth;
+    expand_lua_tags_in(document.children().collect(), ctx, &tags, 0, max_depth)?;
     Ok(document)
 }
 
+fn expand_lua_tags_in(
+    nodes: Vec<NodeRef>, ctx: &RenderContext, tags: &Table, depth: usize, max_depth: usize,
+) -> Result<()> {
+    let lua = &ctx.lua()?.lua;
+    for node in nodes {
+        let Some(element) = node.as_element() else {
+            continue;
+        };
+        let name = element.name.local.to_string();
+        let handler: Option<mlua::Function> = tags
+            .get(name.as_str())
+            .map_err(|e| RenderError::LuaError(format!("Invalid handler for <{name}>: {e}")))?;
+        let Some(handler) = handler else {
+            if !is_raw_element(&node) {
+                expand_lua_tags_in(node.children().collect(), ctx, tags, depth, max_depth)?;
+            }
+            continue;
+        };
+        if depth >= max_depth {
+            return Err(RenderError::LuaError(format!("<{name}> nests custom tags deeper than {max_depth}")));
+        }
+        let attrs: BTreeMap<String, String> = element
+            .attributes
+            .borrow()
+            .map
+            .iter()
+            .map(|(name, attr)| (name.local.to_string(), attr.value.clone()))
+            .collect();
+        let inner_html: String = node.children().map(|c| c.to_string()).collect();
+        let html: Option<String> = lua
+            .create_table_from(attrs)
+            .and_then(|attrs| handler.call((attrs, inner_html)))
+            .map_err(|e| RenderError::LuaError(format!("Tag <{name}> failed: {e}")))?;
+        let output = parse_html_fragment(&html.unwrap_or_default());
+        for child in &output {
+            node.insert_before(child.clone());
+        }
+        node.detach();
+        ctx.mark_output(&output);
+        expand_lua_tags_in(output, ctx, tags, depth + 1, max_depth)?;
+    }
+    Ok(())
+}
+
+/// Renders a run of sibling nodes for `execute_lua`. `env` holds the loop variables of the enclosing `<for>`
+/// elements, if any.
+fn render_nodes(nodes: Vec<NodeRef>, ctx: &RenderContext, env: Option<&Table>) -> Result<()> {
+    // Whether the last `<if>` among these siblings was shown, for a following `<else>`.
+    let mut last_if = None;
+    for node in nodes {
+        let is_output = ctx.is_output(&node);
+        if let Some(text) = node.as_text() {
+            if !is_output && text.borrow().contains("{{") {
+                let replaced = interpolate_expressions(&text.borrow(), &ctx.lua()?.lua, env)?;
+                if let Some(replaced) = replaced {
+                    *text.borrow_mut() = replaced;
+                }
+            }
+            if !text.borrow().trim().is_empty() {
+                last_if = None;
+            }
+            continue;
+        }
+        let Some(element) = node.as_element() else {
+            continue;
+        };
+        if is_output {
+            // Output can still contain markup, e.g. the components of an `<include>` a `phase="pre"` block printed.
+            last_if = None;
+            if !is_raw_element(&node) {
+                render_nodes(node.children().collect(), ctx, env)?;
+            }
+            continue;
+        }
+        match &*element.name.local {
+            "if" => {
+                let cond = element.attributes.borrow().get("cond").map(str::to_string);
+                let cond = cond.ok_or_else(|| RenderError::ParseError("<if> needs a cond attribute".to_string()))?;
+                let shown = !matches!(eval(&ctx.lua()?.lua, env, &cond)?, Value::Nil | Value::Boolean(false));
+                expand_branch(&node, shown, ctx, env)?;
+                last_if = Some(shown);
+            }
+            "else" => {
+                let if_shown = last_if
+                    .take()
+                    .ok_or_else(|| RenderError::ParseError("<else> must follow an <if>".to_string()))?;
+                expand_branch(&node, !if_shown, ctx, env)?;
+            }
+            "for" => {
+                expand_for(&node, ctx, env)?;
+                last_if = None;
+            }
+            "lua" => {
+                let raw = element.attributes.borrow().get("mode") == Some("html");
+                run_lua_block(&node, ctx, raw, env)?;
+                last_if = None;
+            }
+            _ => {
+                last_if = None;
+                if !is_raw_element(&node) {
+                    interpolate_attributes(&node, element, ctx, env)?;
+                    render_nodes(node.children().collect(), ctx, env)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces an `<if>` or `<else>` with its rendered children, or removes it when it isn't `shown`.
+fn expand_branch(node: &NodeRef, shown: bool, ctx: &RenderContext, env: Option<&Table>) -> Result<()> {
+    let children: Vec<_> = if shown { node.children().collect() } else { Vec::new() };
+    for child in &children {
+        node.insert_before(child.clone());
+    }
+    node.detach();
+    render_nodes(children, ctx, env)
+}
+
+/// Replaces a `<for>` with a rendered copy of its children per item. One name iterates the sequence values of the
+/// table; two names iterate all of its keys and values.
+fn expand_for(node: &NodeRef, ctx: &RenderContext, env: Option<&Table>) -> Result<()> {
+    let each = node
+        .as_element()
+        .and_then(|e| e.attributes.borrow().get("each").map(str::to_string))
+        .ok_or_else(|| RenderError::ParseError("<for> needs an each attribute".to_string()))?;
+    let invalid = || RenderError::ParseError(format!("Invalid <for each=\"{each}\">, expected \"item in items\""));
+    let (names, expr) = each.split_once(" in ").ok_or_else(invalid)?;
+    let names: Vec<_> = names.split(',').map(str::trim).collect();
+    let is_name = |n: &&str| {
+        n.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    if names.len() > 2 || !names.iter().all(is_name) {
+        return Err(invalid());
+    }
+
+    let lua = &ctx.lua()?.lua;
+    let Value::Table(items) = eval(lua, env, expr)? else {
+        return Err(RenderError::LuaError(format!("<for each> expects a table, `{}` is not one", expr.trim())));
+    };
+    let entries: mlua::Result<Vec<(Value, Value)>> = if names.len() == 1 {
+        items.sequence_values().map(|v| v.map(|v| (Value::Nil, v))).collect()
+    } else {
+        items.pairs().collect()
+    };
+    let entries = entries.map_err(|e| RenderError::LuaError(format!("Failed to iterate `{}`: {e}", expr.trim())))?;
+
+    let template: Vec<_> = node.children().collect();
+    for (key, value) in entries {
+        let scope = loop_env(lua, env)?;
+        let bound = match names[..] {
+            [key_name, value_name] => scope
+                .raw_set(key_name, key)
+                .and_then(|()| scope.raw_set(value_name, value)),
+            _ => scope.raw_set(names[0], value),
+        };
+        bound.map_err(|e| RenderError::LuaError(format!("Failed to set loop variable: {e}")))?;
+        let copies: Vec<_> = template.iter().map(deep_clone).collect();
+        for (original, copy) in template.iter().zip(&copies) {
+            ctx.copy_output_marks(original, copy);
+            node.insert_before(copy.clone());
+        }
+        render_nodes(copies, ctx, Some(&scope))?;
+    }
+    node.detach();
+    Ok(())
+}
+
+fn interpolate_attributes(
+    node: &NodeRef, element: &ElementData, ctx: &RenderContext, env: Option<&Table>,
+) -> Result<()> {
+    let needs_lua = element
+        .attributes
+        .borrow()
+        .map
+        .iter()
+        .any(|(name, attr)| name.local.starts_with(LUA_ATTRIBUTE_PREFIX) || attr.value.contains("{{"));
+    if !needs_lua {
+        return Ok(());
+    }
+    let lua = &ctx.lua()?.lua;
+    let from_props = ctx.output_attributes(node);
+    let mut attrs = element.attributes.borrow_mut();
+    let lua_attrs: Vec<_> = attrs
+        .map
+        .keys()
+        .filter(|name| name.local.starts_with(LUA_ATTRIBUTE_PREFIX))
+        .map(|name| name.local.to_string())
+        .collect();
+    for (name, attr) in &mut attrs.map {
+        if from_props.iter().any(|p| *p == *name.local) {
+            continue;
+        }
+        if let Some(replaced) = interpolate_expressions(&attr.value, lua, env)? {
+            attr.value = replaced;
+        }
+    }
+    for lua_attr in lua_attrs {
+        let expr = attrs.remove(lua_attr.as_str()).map(|a| a.value).unwrap_or_default();
+        let name = &lua_attr[LUA_ATTRIBUTE_PREFIX.len()..];
+        match eval(lua, env, &expr)? {
+            Value::Nil | Value::Boolean(false) => {}
+            Value::Boolean(true) => {
+                attrs.insert(name, String::new());
+            }
+            value => {
+                attrs.insert(name, value_to_string(&value)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `node` is part of a `<lua>` block's source, such as markup in a Lua string.
+#[must_use]
+pub fn inside_lua(node: &NodeRef) -> bool {
+    node.ancestors()
+        .any(|a| a.as_element().is_some_and(|e| &*e.name.local == "lua"))
+}
+
+fn is_raw_element(node: &NodeRef) -> bool {
+    node.as_element()
+        .is_some_and(|e| matches!(&*e.name.local, "lua" | "script" | "style" | "pre" | "code"))
+}
+
+fn eval(lua: &Lua, env: Option<&Table>, expr: &str) -> Result<Value> {
+    let mut chunk = lua.load(format!("return {expr}"));
+    if let Some(env) = env {
+        chunk = chunk.set_environment(env.clone());
+    }
+    chunk
+        .eval()
+        .map_err(|e| RenderError::LuaError(format!("Failed to evaluate Lua expression `{}`: {e}", expr.trim())))
+}
+
+fn eval_to_string(lua: &Lua, env: Option<&Table>, expr: &str) -> Result<String> {
+    value_to_string(&eval(lua, env, expr)?)
+}
+
+fn value_to_string(value: &Value) -> Result<String> {
+    match value {
+        Value::Nil => Ok(String::new()),
+        value => Ok(value
+            .to_string()
+            .map_err(|e| RenderError::LuaError(format!("Failed to convert Lua value to string: {e}")))?),
+    }
+}
+
+/// Creates an `_ENV` for a scope: globals defined in it stay in the table, while reads fall through to `parent`, or
+/// to the page globals.
+fn scoped_env(lua: &Lua, parent: Option<&Table>) -> Result<Table> {
+    let create = || -> mlua::Result<Table> {
+        let env = lua.create_table()?;
+        let meta = lua.create_table()?;
+        meta.set("__index", parent.cloned().unwrap_or_else(|| lua.globals()))?;
+        env.set_metatable(Some(meta));
+        Ok(env)
+    };
+    create().map_err(|e| RenderError::LuaError(format!("Failed to create Lua scope: {e}")))
+}
+
+/// Creates the `_ENV` of one iteration of a `<for>`: it holds the loop variables, while other reads and writes go
+/// through to `parent`, or to the page globals, so blocks in the loop body can update page state.
+fn loop_env(lua: &Lua, parent: Option<&Table>) -> Result<Table> {
+    let create = || -> mlua::Result<Table> {
+        let env = lua.create_table()?;
+        let meta = lua.create_table()?;
+        let parent = parent.cloned().unwrap_or_else(|| lua.globals());
+        meta.set("__index", parent.clone())?;
+        meta.set("__newindex", parent)?;
+        env.set_metatable(Some(meta));
+        Ok(env)
+    };
+    create().map_err(|e| RenderError::LuaError(format!("Failed to create Lua scope: {e}")))
+}
+
+/// Creates the `_ENV` of a `scope="component"` block inside a `<for>`: globals it defines go to the `component`
+/// scope, while reads try that scope first and then the loop variables in `loop_env`.
+fn loop_scoped_env(lua: &Lua, component: Table, loop_env: Table) -> Result<Table> {
+    let create = || -> mlua::Result<Table> {
+        let env = lua.create_table()?;
+        let meta = lua.create_table()?;
+        meta.set("__newindex", component.clone())?;
+        let index =
+            lua.create_function(move |_, (_, key): (Table, Value)| match component.raw_get(key.clone())? {
+                Value::Nil => loop_env.get::<Value>(key),
+                value => Ok(value),
+            })?;
+        meta.set("__index", index)?;
+        env.set_metatable(Some(meta));
+        Ok(env)
+    };
+    create().map_err(|e| RenderError::LuaError(format!("Failed to create Lua scope: {e}")))
+}
+
+/// Names a block's chunk after its file and the line it starts on, e.g. `pages/blog/index.html:42`, so errors and
+/// tracebacks point there. Blocks not read from a file keep Lua's default name.
+fn chunk_name(node: &NodeRef, config: &Config) -> Option<String> {
+    let element = node.as_element()?;
+    let attrs = element.attributes.borrow();
+    let source = attrs.get(SOURCE_ATTRIBUTE)?;
+    let line = attrs.get(LINE_ATTRIBUTE)?;
+    Some(format!("={}:{line}", source_label(Path::new(source), config)))
+}
+
+/// Builds the error for a failed `<lua>` block, locating it with the attributes `read_doc_from_file` left on it.
+fn lua_block_error(node: &NodeRef, error: &mlua::Error, code: String) -> RenderError {
+    let mut block_error = LuaBlockError::new(error, code);
+    if let Some(element) = node.as_element() {
+        let attrs = element.attributes.borrow();
+        let number = |name| attrs.get(name).and_then(|v: &str| v.parse().ok());
+        if let (Some(source), Some(block), Some(line)) =
+            (attrs.get(SOURCE_ATTRIBUTE), number(BLOCK_ATTRIBUTE), number(LINE_ATTRIBUTE))
+        {
+            block_error = block_error.located(PathBuf::from(source), block, line);
+        }
+    }
+    RenderError::LuaBlockError(Box::new(block_error))
+}
+
+/// Rebuilds the source of a `<lua>` element. The HTML parser turns markup inside Lua strings (`"<li>"`) into
+/// child elements, so those are serialized back instead of being dropped.
+fn lua_source(node: &NodeRef) -> String {
+    node.children()
+        .map(|child| match child.as_text() {
+            Some(text) => text.borrow().clone(),
+            None => child.to_string(),
+        })
+        .collect()
+}
+
 pub fn process_markdown(document: NodeRef) -> Result<NodeRef> {
     let markdown_elements: Vec<_> = match document.select("markdown") {
         Ok(e) => e.collect(),
-        Err(()) => return Err(anyhow!("Unable to find markdown elements")),
+        Err(()) => return Err(RenderError::ParseError("Unable to find markdown elements".to_string())),
     };
     for node in markdown_elements {
-        if let Some(text_node) = node.as_node().first_child() {
-            if let Some(markdown_text) = text_node.as_text() {
-                let borrowed_text = markdown_text.borrow();
-                let parser = Parser::new_ext(&borrowed_text, Options::all());
-                let mut html_output = String::new();
-                html::push_html(&mut html_output, parser);
-                let html_fragment = kuchikiki::parse_html().one(html_output);
-                for child in html_fragment.children() {
-                    node.as_node().insert_before(child);
-                }
-                // Remove the original markdown node.
-                node.as_node().detach();
+        if inside_lua(node.as_node()) {
+            continue;
+        }
+        if let Some(text_node) = node.as_node().first_child()
+            && let Some(markdown_text) = text_node.as_text()
+        {
+            let borrowed_text = markdown_text.borrow();
+            let parser = Parser::new_ext(&borrowed_text, Options::all());
+            let mut html_output = String::new();
+            html::push_html(&mut html_output, parser);
+            let html_fragment = kuchikiki::parse_html().one(html_output);
+            for child in html_fragment.children() {
+                node.as_node().insert_before(child);
             }
+            // Remove the original markdown node.
+            node.as_node().detach();
         }
     }
     Ok(document)
 }
 
-pub fn expand_template(document: NodeRef, component_path: &PathBuf, include_from: Option<&NodeRef>) -> Result<NodeRef> {
+/// Replaces each `<include>` with its component, after running the `<lua phase="pre">` blocks of the document and
+/// of every component in `ctx`.
+pub fn expand_template(
+    document: NodeRef, component_path: &PathBuf, include_from: Option<&NodeRef>, ctx: &RenderContext,
+) -> Result<NodeRef> {
+    let max_depth = ctx.renderer.config().templates.max_include_depth;
+    expand_template_with_chain(document, component_path, include_from, ctx, &mut Vec::new(), max_depth)
+}
+
+/// `chain` holds the components currently being expanded, outermost first, to catch include cycles.
+fn expand_template_with_chain(
+    document: NodeRef, component_path: &PathBuf, include_from: Option<&NodeRef>, ctx: &RenderContext,
+    chain: &mut Vec<PathBuf>, max_depth: usize,
+) -> Result<NodeRef> {
     if let Some(from_node) = include_from {
         for i in document
             .select("includeelement")
-            .map_err(|()| anyhow!("Error finding includeelement"))?
+            .map_err(|()| RenderError::ParseError("Error finding includeelement".to_string()))?
         {
             if let Some(name) = i.attributes.borrow().get("name") {
                 let exported = from_node
                     .select_first(format!("exportelement.{name}").as_str())
-                    .map_err(|()| anyhow!("Error finding exportelement"))?;
+                    .map_err(|()| RenderError::IncludeError(format!("No exportelement for includeelement '{name}'")))?;
                 exported
                     .as_node()
                     .children()
@@ -104,10 +713,11 @@ pub fn expand_template(document: NodeRef, component_path: &PathBuf, include_from
             i.as_node().detach();
         }
     }
+    execute_pre_lua(&document, ctx)?;
 
     for i in document
         .select("include")
-        .map_err(|()| anyhow!("Error finding include"))?
+        .map_err(|()| RenderError::ParseError("Error finding include".to_string()))?
         .collect::<Vec<_>>()
     {
         let attrs = match i.as_node().as_element() {
@@ -115,13 +725,32 @@ pub fn expand_template(document: NodeRef, component_path: &PathBuf, include_from
             None => continue,
         };
         if let Some(include_path) = attrs.get("path") {
-            let mut item_path = component_path.clone();
-            item_path.push(include_path);
-            let new_node = read_doc_from_file(item_path)?;
-            let replaced_node = expand_template(new_node, component_path, Some(i.as_node()))?;
+            let item_path = resolve_within(component_path, Path::new(include_path))?;
+            if chain.contains(&item_path) || chain.len() >= max_depth {
+                let cycle = chain.contains(&item_path);
+                chain.push(item_path);
+                let chain_text = format_include_chain(chain, component_path);
+                return Err(if cycle {
+                    RenderError::IncludeError(format!("cycle detected: {chain_text}"))
+                } else {
+                    RenderError::IncludeError(format!("maximum depth of {max_depth} exceeded: {chain_text}"))
+                });
+            }
+            let new_node = read_doc_from_file(&item_path)?;
+            let props: BTreeMap<String, String> = attrs
+                .map
+                .iter()
+                .filter(|(name, _)| &*name.local != "path")
+                .map(|(name, attr)| (name.local.to_string(), attr.value.clone()))
+                .collect();
+            apply_props(&new_node, &props, NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed), ctx)?;
+            chain.push(item_path);
+            let replaced_node =
+                expand_template_with_chain(new_node, component_path, Some(i.as_node()), ctx, chain, max_depth)?;
+            chain.pop();
             replaced_node
                 .select_first("html")
-                .map_err(|()| anyhow!("Error finding html"))?
+                .map_err(|()| RenderError::IncludeError(format!("Component {include_path} has no html root")))?
                 .as_node()
                 .children()
                 .rev()
@@ -134,53 +763,493 @@ pub fn expand_template(document: NodeRef, component_path: &PathBuf, include_from
     Ok(document)
 }
 
+fn format_include_chain(chain: &[PathBuf], component_path: &Path) -> String {
+    let root = component_path
+        .canonicalize()
+        .unwrap_or_else(|_| component_path.to_path_buf());
+    chain
+        .iter()
+        .map(|p| p.strip_prefix(&root).unwrap_or(p).display().to_string())
+        .collect::<Vec<_>>()
+        .join(" → ")
+}
+
+/// Fills `{{name}}` placeholders in a freshly read component from its include attributes and attaches the props and
+/// the include's `instance` id to the component's own `<lua>` blocks, before nested includes are expanded into it.
+/// Prop values are marked as output in `ctx`, so `{{ }}` in them stays text.
+fn apply_props(
+    component: &NodeRef, props: &BTreeMap<String, String>, instance: usize, ctx: &RenderContext,
+) -> Result<()> {
+    for node in component.descendants().collect::<Vec<_>>() {
+        if let Some(text) = node.as_text() {
+            if inside_lua(&node) {
+                continue;
+            }
+            let replaced = interpolate_props(&text.borrow(), props)?;
+            if replaced.is_some() && props.values().any(|v| v.contains("{{")) {
+                split_props(&node, props, ctx);
+            } else if let Some(replaced) = replaced {
+                *text.borrow_mut() = replaced;
+            }
+        } else if let Some(element) = node.as_element() {
+            let mut attrs = element.attributes.borrow_mut();
+            for (name, attr) in &mut attrs.map {
+                let mut from_props = false;
+                let replaced = interpolate(&attr.value, |key| {
+                    let value = props.get(key).cloned();
+                    from_props |= value.as_ref().is_some_and(|v| v.contains("{{"));
+                    Ok(value)
+                })?;
+                if let Some(replaced) = replaced {
+                    attr.value = replaced;
+                }
+                if from_props {
+                    ctx.mark_output_attribute(&node, &name.local);
+                }
+            }
+            if &*element.name.local == "lua" && !attrs.contains(PROPS_ATTRIBUTE) {
+                let json = serde_json::to_string(props).map_err(|e| RenderError::ParseError(e.to_string()))?;
+                attrs.insert(PROPS_ATTRIBUTE, json);
+                attrs.insert(INSTANCE_ATTRIBUTE, instance.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces each `{{ ... }}` in `text` with the result of `resolve` for its trimmed contents, keeping placeholders
+/// `resolve` returns `None` for and escaped ones, `\{{ ... }}`. Returns `None` when nothing was replaced.
+fn interpolate(text: &str, mut resolve: impl FnMut(&str) -> Result<Option<String>>) -> Result<Option<String>> {
+    if !text.contains("{{") {
+        return Ok(None);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut replaced = false;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        match resolve(rest[start + 2..start + len].trim())? {
+            Some(value) => {
+                out.push_str(&value);
+                replaced = true;
+            }
+            None => out.push_str(&rest[start..start + len + 2]),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(replaced.then_some(out))
+}
+
+/// Evaluates the placeholders in page text or an attribute value, then unescapes `\{{` to `{{`.
+fn interpolate_expressions(text: &str, lua: &Lua, env: Option<&Table>) -> Result<Option<String>> {
+    let replaced = interpolate(text, |expr| eval_to_string(lua, env, expr).map(Some))?;
+    let text = replaced.as_deref().unwrap_or(text);
+    if text.contains(ESCAPED_PLACEHOLDER) {
+        return Ok(Some(text.replace(ESCAPED_PLACEHOLDER, "{{")));
+    }
+    Ok(replaced)
+}
+
+fn interpolate_props(text: &str, props: &BTreeMap<String, String>) -> Result<Option<String>> {
+    interpolate(text, |key| Ok(props.get(key).cloned()))
+}
+
+/// Fills the placeholders of the text node `node` from `props`, moving each value into a text node of its own that
+/// is marked as output, with the text around it kept as markup.
+fn split_props(node: &NodeRef, props: &BTreeMap<String, String>, ctx: &RenderContext) {
+    let Some(text) = node.as_text() else {
+        return;
+    };
+    let source = text.borrow().clone();
+    let mut markup = String::new();
+    let mut rest = source.as_str();
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            markup.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(len) = rest[start..].find("}}") else {
+            break;
+        };
+        markup.push_str(&rest[..start]);
+        match props.get(rest[start + 2..start + len].trim()) {
+            Some(value) => {
+                if !markup.is_empty() {
+                    node.insert_before(NodeRef::new_text(std::mem::take(&mut markup)));
+                }
+                let value = NodeRef::new_text(value.as_str());
+                node.insert_before(value.clone());
+                ctx.mark_output(&[value]);
+            }
+            None => markup.push_str(&rest[start..start + len + 2]),
+        }
+        rest = &rest[start + len + 2..];
+    }
+    markup.push_str(rest);
+    *text.borrow_mut() = markup;
+}
+
 pub fn process_syntax_highlighting(document: NodeRef) -> Result<NodeRef> {
-    let config = get_config();
-    let ps = SyntaxSet::load_defaults_newlines();
-    let mut ts = ThemeSet::load_defaults();
+    highlight_document(document, Renderer::global())
+}
+
+/// Replaces each `<syntaxhighlight>` with highlighted code, using the syntaxes and themes of `renderer`.
+pub fn highlight_document(document: NodeRef, renderer: &Renderer) -> Result<NodeRef> {
+    let config = renderer.config();
     let syntax_elements: Vec<_> = match document.select("syntaxhighlight") {
         Ok(e) => e.collect(),
-        Err(()) => return Err(anyhow!("Unable to find syntaxhighlight elements")),
+        Err(()) => return Err(RenderError::ParseError("Unable to find syntaxhighlight elements".to_string())),
     };
-    if !syntax_elements.is_empty() && config.syntax_highlighting.load_custom_themes {
-        let _ = ts.add_from_folder(&config.paths.themes);
-    }
     for node in syntax_elements {
+        if inside_lua(node.as_node()) {
+            continue;
+        }
         let attrs = match node.as_node().as_element() {
             Some(e) => e.attributes.borrow(),
             None => continue,
         };
-        let language = attrs.get("lang").unwrap_or("text");
-        let theme_name = attrs.get("theme").unwrap_or(&config.syntax_highlighting.default_theme);
-        if let Some(text_node) = node.as_node().first_child() {
-            if let Some(code_text) = text_node.as_text() {
-                let syntax = ps
-                    .find_syntax_by_extension(language)
-                    .or_else(|| ps.find_syntax_by_name(language))
-                    .unwrap_or_else(|| ps.find_syntax_plain_text());
-                let theme = &ts.themes[theme_name];
-                let mut h = HighlightLines::new(syntax, theme);
-                let mut html_output = String::new();
-                write!(html_output, r#"<pre class="syntax-highlight" data-lang="{language}">"#)?;
-                html_output.push_str("<code>");
-                for line in LinesWithEndings::from(&code_text.borrow()) {
-                    let ranges: Vec<(Style, &str)> = h.highlight_line(line, &ps)?;
-                    let escaped = styled_line_to_highlighted_html(&ranges[..], IncludeBackground::No)?;
-                    html_output.push_str(&escaped);
+        let snippet = match attrs.get("src") {
+            Some(src) => Some(read_snippet(&config.paths.snippets, src, &attrs)?),
+            None => None,
+        };
+        let language = attrs
+            .get("lang")
+            .or_else(|| snippet.as_ref().and_then(|s| s.language.as_deref()))
+            .unwrap_or("text");
+        let mut lines = LineOptions::from_attributes(&attrs)?;
+        let numbered = ["linenos", "start", "highlight"]
+            .iter()
+            .any(|name| attrs.contains(*name));
+        let code = match &snippet {
+            Some(snippet) => {
+                if !attrs.contains("start") {
+                    lines.start = snippet.first_line;
                 }
-                html_output.push_str("</code></pre>");
-                let html_fragment = kuchikiki::parse_html().one(html_output);
-                for child in html_fragment.children() {
-                    node.as_node().insert_before(child);
+                Some(snippet.code.clone())
+            }
+            None => node.as_node().first_child().and_then(|child| {
+                child.as_text().map(|text| {
+                    let text = text.borrow();
+                    // Numbered lines have to start at the first line of code, not at the newline after the tag.
+                    if numbered {
+                        trim_code_block(&text)
+                    } else {
+                        text.as_str()
+                    }
+                    .to_string()
+                })
+            }),
+        };
+        if let Some(code) = code {
+            let style = if config.syntax_highlighting.css_classes {
+                // The theme comes from the stylesheet the page links, so a per-block one can't apply.
+                if let Some(theme) = attrs.get("theme") {
+                    eprintln!(
+                        "Warning: Ignoring theme=\"{theme}\" on <syntaxhighlight>, syntax_highlighting.css_classes is on"
+                    );
                 }
-                // Remove the original syntaxhighlight node.
-                node.as_node().detach();
+                LineStyle::Classed
+            } else {
+                let default_theme = &config.syntax_highlighting.default_theme;
+                LineStyle::Inline(match attrs.get("theme").map(|name| renderer.theme(name)) {
+                    Some(Ok(theme)) => theme,
+                    Some(Err(e)) => {
+                        eprintln!("Warning: {e}");
+                        eprintln!("Using the default theme '{default_theme}'");
+                        renderer.theme(default_theme)?
+                    }
+                    None => renderer.theme(default_theme)?,
+                })
+            };
+            let class = match style {
+                LineStyle::Inline(_) => "syntax-highlight",
+                LineStyle::Classed => &format!("syntax-highlight {HIGHLIGHT_CLASS_PREFIX}code"),
+            };
+            let mut html_output = String::new();
+            write!(html_output, r#"<pre class="{class}" data-lang="{language}">"#)
+                .map_err(|e| RenderError::HighlightError(e.to_string()))?;
+            html_output.push_str("<code>");
+            for (index, line) in highlight_lines(renderer.syntaxes(), style, &code, language)?
+                .iter()
+                .enumerate()
+            {
+                lines.write_line(&mut html_output, lines.start + index, line);
+            }
+            html_output.push_str("</code></pre>");
+            for child in parse_html_fragment(&html_output) {
+                node.as_node().insert_before(child);
             }
+            // Remove the original syntaxhighlight node.
+            node.as_node().detach();
         }
     }
     Ok(document)
 }
 
+/// Line numbering and emphasis from the `linenos`, `start` and `highlight` attributes of `<syntaxhighlight>`. Each
+/// line is wrapped in `<span class="line" data-line="N">`, with `highlighted` added to the class of emphasised lines.
+struct LineOptions {
+    linenos: bool,
+    start: usize,
+    /// Ranges of displayed line numbers, so they count from `start`.
+    highlight: Vec<RangeInclusive<usize>>,
+}
+
+impl LineOptions {
+    fn from_attributes(attrs: &Attributes) -> Result<Self> {
+        let start = match attrs.get("start") {
+            Some(value) => value.trim().parse().map_err(|_| {
+                RenderError::ParseError(format!("Invalid <syntaxhighlight> start=\"{value}\", expected a line number"))
+            })?,
+            None => 1,
+        };
+        let highlight = match attrs.get("highlight") {
+            Some(value) => parse_line_ranges(value).ok_or_else(|| {
+                RenderError::ParseError(format!(
+                    "Invalid <syntaxhighlight> highlight=\"{value}\", expected line ranges such as \"3-5,9\""
+                ))
+            })?,
+            None => Vec::new(),
+        };
+        Ok(Self {
+            linenos: attrs.contains("linenos"),
+            start,
+            highlight,
+        })
+    }
+
+    fn write_line(&self, out: &mut String, number: usize, line: &str) {
+        let highlighted = self.highlight.iter().any(|range| range.contains(&number));
+        let class = if highlighted { "line highlighted" } else { "line" };
+        let _ = write!(out, r#"<span class="{class}" data-line="{number}">"#);
+        if self.linenos {
+            let _ = write!(out, r#"<span class="lineno">{number}</span>"#);
+        }
+        out.push_str(line);
+        out.push_str("</span>");
+    }
+}
+
+/// Parses line ranges such as `3-5,9`, or returns `None` if any part isn't a line number or an ascending range.
+fn parse_line_ranges(value: &str) -> Option<Vec<RangeInclusive<usize>>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let (first, last): (usize, usize) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+            (first <= last).then_some(first..=last)
+        })
+        .collect()
+}
+
+/// Code read by `<syntaxhighlight src="...">`, sliced by its `lines` or `region` attribute.
+struct Snippet {
+    code: String,
+    /// The file line number of the first line of `code`, used as the default `start`.
+    first_line: usize,
+    /// The file extension, used as the default `lang`.
+    language: Option<String>,
+}
+
+fn read_snippet(snippets: &Path, src: &str, attrs: &Attributes) -> Result<Snippet> {
+    let path = resolve_within(snippets, Path::new(src))?;
+    // A directory or unreadable file is an authoring mistake like a missing one, not a server failure.
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(_) if path.is_dir() => return Err(RenderError::NotFound(PathBuf::from(src))),
+        Err(e) => return Err(RenderError::IncludeError(format!("Cannot read snippet {src}: {e}"))),
+    };
+    let all: Vec<&str> = source.split_inclusive('\n').collect();
+    let (first, selected) = match (attrs.get("lines"), attrs.get("region")) {
+        (Some(_), Some(_)) => {
+            return Err(RenderError::ParseError(format!(
+                "<syntaxhighlight src=\"{src}\"> takes either lines or region, not both"
+            )));
+        }
+        (Some(value), None) => match parse_line_ranges(value).as_deref() {
+            Some([range]) if *range.start() > 0 && *range.end() <= all.len() => {
+                (*range.start(), &all[range.start() - 1..*range.end()])
+            }
+            _ => {
+                return Err(RenderError::IncludeError(format!(
+                    "Invalid lines=\"{value}\" for {src}, expected one range such as \"10-40\" within its {} lines",
+                    all.len()
+                )));
+            }
+        },
+        (None, Some(name)) => {
+            let (start, end) = find_region(&all, name)
+                .ok_or_else(|| RenderError::IncludeError(format!("No region '{name}' with an endregion in {src}")))?;
+            (start + 1, &all[start..end])
+        }
+        (None, None) => (1, &all[..]),
+    };
+    Ok(Snippet {
+        code: selected.concat(),
+        first_line: first,
+        language: path.extension().and_then(|e| e.to_str()).map(str::to_string),
+    })
+}
+
+enum RegionMarker<'a> {
+    Start(&'a str),
+    End,
+}
+
+/// Reads a `// region: name` or `// endregion` line; `#`, `--`, `/* */` and `<!-- -->` comments work too.
+fn region_marker(line: &str) -> Option<RegionMarker<'_>> {
+    let text = ["//", "#", "--", "/*", "<!--"]
+        .iter()
+        .find_map(|p| line.trim().strip_prefix(p))?;
+    let text = text
+        .trim_end()
+        .strip_suffix("*/")
+        .or_else(|| text.trim_end().strip_suffix("-->"))
+        .unwrap_or(text);
+    match text.trim() {
+        "endregion" => Some(RegionMarker::End),
+        text => text
+            .strip_prefix("region:")
+            .map(|name| RegionMarker::Start(name.trim())),
+    }
+}
+
+/// Finds the lines between the `region: name` marker and its `endregion`, skipping over nested regions. Returns the
+/// index range of the lines, markers excluded.
+fn find_region(lines: &[&str], name: &str) -> Option<(usize, usize)> {
+    let start = lines
+        .iter()
+        .position(|line| matches!(region_marker(line), Some(RegionMarker::Start(n)) if n == name))?
+        + 1;
+    let mut depth = 0usize;
+    for (index, line) in lines.iter().enumerate().skip(start) {
+        match region_marker(line) {
+            Some(RegionMarker::Start(_)) => depth += 1,
+            Some(RegionMarker::End) if depth == 0 => return Some((start, index)),
+            Some(RegionMarker::End) => depth -= 1,
+            None => {}
+        }
+    }
+    None
+}
+
+/// Drops the newline after `<syntaxhighlight>`, as `<pre>` does, and the indentation before `</syntaxhighlight>`, for
+/// blocks with numbered lines.
+fn trim_code_block(code: &str) -> &str {
+    let code = code
+        .strip_prefix("\r\n")
+        .or_else(|| code.strip_prefix('\n'))
+        .unwrap_or(code);
+    match code.rfind('\n') {
+        Some(end) if code[end + 1..].trim().is_empty() => &code[..=end],
+        _ => code,
+    }
+}
+
+/// Highlights `code` with the default theme of `renderer`, returning one line of inline-styled HTML per source line.
+pub fn highlight_code(renderer: &Renderer, code: &str, language: &str) -> Result<Vec<String>> {
+    let theme = renderer.theme(&renderer.config().syntax_highlighting.default_theme)?;
+    highlight_lines(renderer.syntaxes(), LineStyle::Inline(theme), code, language)
+}
+
+/// How highlighted code is coloured: inline `style` attributes from a theme, or `hl-` prefixed scope classes for a
+/// stylesheet from [`Renderer::theme_css`].
+#[derive(Clone, Copy)]
+enum LineStyle<'a> {
+    Inline(&'a Theme),
+    Classed,
+}
+
+/// Highlights `code` line by line; each returned line keeps its trailing newline and closes the spans it opens.
+fn highlight_lines(ps: &SyntaxSet, style: LineStyle, code: &str, language: &str) -> Result<Vec<String>> {
+    let syntax = ps
+        .find_syntax_by_extension(language)
+        .or_else(|| ps.find_syntax_by_name(language))
+        .unwrap_or_else(|| ps.find_syntax_plain_text());
+    let highlight_error = |e: syntect::Error| RenderError::HighlightError(e.to_string());
+    match style {
+        LineStyle::Inline(theme) => {
+            let mut h = HighlightLines::new(syntax, theme);
+            LinesWithEndings::from(code)
+                .map(|line| {
+                    let ranges: Vec<(Style, &str)> = h.highlight_line(line, ps).map_err(highlight_error)?;
+                    styled_line_to_highlighted_html(&ranges[..], IncludeBackground::No).map_err(highlight_error)
+                })
+                .collect()
+        }
+        LineStyle::Classed => {
+            let mut state = ParseState::new(syntax);
+            let mut stack = ScopeStack::new();
+            LinesWithEndings::from(code)
+                .map(|line| {
+                    // Reopen the scopes still open from earlier lines so each line stands on its own.
+                    let mut html = String::new();
+                    for scope in stack.as_slice() {
+                        let classes: Vec<_> = scope
+                            .build_string()
+                            .split('.')
+                            .map(|atom| format!("{HIGHLIGHT_CLASS_PREFIX}{atom}"))
+                            .collect();
+                        let _ = write!(html, r#"<span class="{}">"#, classes.join(" "));
+                    }
+                    let ops = state
+                        .parse_line(line, ps)
+                        .map_err(|e| RenderError::HighlightError(e.to_string()))?;
+                    let (spans, _) =
+                        line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut stack).map_err(highlight_error)?;
+                    html.push_str(&spans);
+                    html.push_str(&"</span>".repeat(stack.len()));
+                    Ok(html)
+                })
+                .collect()
+        }
+    }
+}
+
+/// Adds the `.tmTheme` files under `themes_path` to `ts`, skipping any that resolve outside it.
+pub fn load_custom_themes(ts: &mut ThemeSet, themes_path: &Path) {
+    for (name, path) in custom_theme_paths(themes_path) {
+        match ThemeSet::get_theme(&path) {
+            Ok(theme) => {
+                ts.themes.insert(name, theme);
+            }
+            Err(e) => eprintln!("Warning: Failed to load theme {}: {e}", path.display()),
+        }
+    }
+}
+
+/// The `.tmTheme` files under `themes_path` by theme name, resolved and without any that resolve outside it.
+#[must_use]
+pub fn custom_theme_paths(themes_path: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(paths) = ThemeSet::discover_theme_paths(themes_path) else {
+        return Vec::new();
+    };
+    let mut themes = Vec::new();
+    for path in paths {
+        let (Some(name), Ok(relative)) = (path.file_stem().and_then(|n| n.to_str()), path.strip_prefix(themes_path))
+        else {
+            continue;
+        };
+        match resolve_within(themes_path, relative) {
+            Ok(resolved) => themes.push((name.to_string(), resolved)),
+            Err(_) => eprintln!("Warning: Skipping theme outside {}: {}", themes_path.display(), path.display()),
+        }
+    }
+    themes
+}
+
 pub fn generate_footnotes(document: NodeRef) -> Result<NodeRef> {
     let Ok(footnote_container) = document.select_first("footnotecontainer") else {
         return Ok(document);
@@ -189,20 +1258,21 @@ pub fn generate_footnotes(document: NodeRef) -> Result<NodeRef> {
     let ctx_name = QualName::new(None, Namespace::from("http://www.w3.org/1999/xhtml"), LocalName::from("div"));
     for (i, footnote) in document
         .select("footnote")
-        .map_err(|()| anyhow!("Failed to get footnote"))?
+        .map_err(|()| RenderError::ParseError("Failed to get footnote".to_string()))?
         .enumerate()
     {
         let i = i + 1;
-        let fn_text = footnote.text_contents();
+        // Escaped again, since `text_contents` decodes the entities the footnote was written or printed with.
+        let fn_text = escape_html(&footnote.text_contents());
         let sup_tag = kuchikiki::parse_fragment(ctx_name.clone(), Vec::new())
             .one(format!("<a href=#ft-text-{i}><sup id=\"ft-sup-{i}\" title=\"{fn_text}\">{i}</sup></a>"))
             .select_first("a")
-            .map_err(|()| anyhow!("parse err"))?;
+            .map_err(|()| RenderError::ParseError("Failed to parse footnote".to_string()))?;
         footnote.as_node().insert_after(sup_tag.as_node().clone());
         let text_tag = kuchikiki::parse_fragment(ctx_name.clone(), Vec::new())
             .one(format!("<p id=\"ft-text-{i}\"><a href=#ft-sup-{i}>{i}:</a> {fn_text}</p>"))
             .select_first("p")
-            .map_err(|()| anyhow!("parse err"))?;
+            .map_err(|()| RenderError::ParseError("Failed to parse footnote".to_string()))?;
         footnote_container.as_node().insert_before(text_tag.as_node().clone());
     }
     while let Ok(i) = document.select_first("footnote") {
@@ -219,6 +1289,11 @@ mod tests {
 
     use super::*;
 
+    /// A renderer on the built-in config, so tests don't depend on the host's config file.
+    fn test_renderer() -> Renderer { Renderer::new(Config::default()).unwrap() }
+
+    fn test_ctx() -> RenderContext { RenderContext::new(Request::default(), test_renderer()) }
+
     #[test]
     fn basic_lua() {
         let page = r#"
@@ -236,7 +1311,7 @@ mod tests {
             </body>
             </html>"#;
         let document = kuchikiki::parse_html().one(page);
-        let d = execute_lua(document).unwrap();
+        let d = execute_lua(document, &test_ctx()).unwrap();
         let text = d.select_first("span").unwrap().as_node().text_contents();
         assert_eq!(text, "Test from Lua!\n");
         assert!(d.select_first("lua").is_err());
@@ -262,7 +1337,7 @@ mod tests {
             </body>
             </html>"#;
         let document = kuchikiki::parse_html().one(page);
-        let d = execute_lua(document).unwrap();
+        let d = execute_lua(document, &test_ctx()).unwrap();
         let text = d.select_first("#ta").unwrap().as_node().text_contents();
         assert_eq!(text, "Test from Lua!\n");
         let text = d.select_first("#tb").unwrap().as_node().text_contents();
@@ -270,6 +1345,96 @@ mod tests {
         assert!(d.select_first("lua").is_err());
     }
 
+    #[test]
+    fn lua_html_output() {
+        let page = r#"
+            <!DOCTYPE html>
+            <html>
+            <body>
+                <ul id="list"><lua>
+                    for _, item in ipairs({"a", "b"}) do
+                        htmlua.print_html("<li>" .. item .. "</li>")
+                    end
+                </lua></ul>
+                <p id="escaped"><lua>htmlua.print("<b>not bold</b>")</lua></p>
+                <p id="mode"><lua mode="html">htmlua.print("<b>bold</b>")</lua></p>
+            </body>
+            </html>"#;
+        let document = kuchikiki::parse_html().one(page);
+        let d = execute_lua(document, &test_ctx()).unwrap();
+        let items: Vec<_> = d.select("#list > li").unwrap().map(|li| li.text_contents()).collect();
+        assert_eq!(items, ["a", "b"]);
+        let escaped = d.select_first("#escaped").unwrap();
+        assert!(escaped.as_node().select_first("b").is_err());
+        assert_eq!(escaped.text_contents(), "<b>not bold</b>");
+        assert_eq!(d.select_first("#mode b").unwrap().text_contents(), "bold");
+    }
+
+    #[test]
+    fn lua_interpolation() {
+        let page = r#"
+            <!DOCTYPE html>
+            <html>
+            <body>
+                <lua>user = { name = "Ada", url = "/u/ada" } count = 3</lua>
+                <a id="link" href="{{ user.url }}?n={{count}}" lua:title="'Profile of ' .. user.name">{{ user.name }}</a>
+                <input id="check" lua:checked="count > 2" lua:disabled="count > 5">
+                <p id="escaped">{{ "&lt;b&gt;" .. count * 2 }}</p>
+                <pre id="raw">{{ not_evaluated }}</pre>
+            </body>
+            </html>"#;
+        let ctx = test_ctx();
+        let document = kuchikiki::parse_html().one(page);
+        let d = execute_lua(document, &ctx).unwrap();
+        let link = d.select_first("#link").unwrap();
+        assert_eq!(link.text_contents(), "Ada");
+        let attrs = link.attributes.borrow();
+        assert_eq!(attrs.get("href"), Some("/u/ada?n=3"));
+        assert_eq!(attrs.get("title"), Some("Profile of Ada"));
+        assert!(attrs.get("lua:title").is_none());
+        let check = d.select_first("#check").unwrap();
+        assert_eq!(check.attributes.borrow().get("checked"), Some(""));
+        assert!(check.attributes.borrow().get("disabled").is_none());
+        let escaped = d.select_first("#escaped").unwrap();
+        assert_eq!(escaped.text_contents(), "<b>6");
+        assert!(escaped.as_node().select_first("b").is_err());
+        assert_eq!(d.select_first("#raw").unwrap().text_contents(), "{{ not_evaluated }}");
+    }
+
+    #[test]
+    fn interpolation_skips_output() {
+        let page = r#"
+            <div>
+                <p id="query">{{ 1 + 1 }} <lua>htmlua.print(htmlua.request.query.q)</lua></p>
+                <p id="html"><lua mode="html">
+                    local q = htmlua.request.query.q
+                    htmlua.print('<span lua:title="1 + 1">' .. q .. '</span><if cond="true">' .. q .. '</if>')
+                </lua></p>
+                <for each="i in { 1 }"><p class="loop"><lua>htmlua.print(htmlua.request.query.q)</lua></p></for>
+                <p id="pre"><lua phase="pre">htmlua.print(htmlua.request.query.q)</lua></p>
+                <div id="prop"><include path="card.html" title="{{ 1 + 1 }}" href="/{{ 2 + 2 }}"></include></div>
+            </div>"#;
+        let mut request = Request::new("GET", "/");
+        request.query_string = "q=%7B%7B%201%20%2B%201%20%7D%7D".to_string();
+        let ctx = RenderContext::new(request, test_renderer());
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let d = expand_template(document, &p, None, &ctx).unwrap();
+        let d = execute_lua(d, &ctx).unwrap();
+        assert_eq!(d.select_first("#query").unwrap().text_contents(), "2 {{ 1 + 1 }}");
+        let span = d.select_first("#html span").unwrap();
+        assert_eq!(span.text_contents(), "{{ 1 + 1 }}");
+        assert_eq!(span.attributes.borrow().get("lua:title"), Some("1 + 1"));
+        assert_eq!(d.select_first("#html if").unwrap().text_contents(), "{{ 1 + 1 }}");
+        assert_eq!(d.select_first(".loop").unwrap().text_contents(), "{{ 1 + 1 }}");
+        assert_eq!(d.select_first("#pre").unwrap().text_contents(), "{{ 1 + 1 }}");
+        let link = d.select_first("#prop a").unwrap();
+        assert_eq!(link.text_contents(), "{{ 1 + 1 }}");
+        assert_eq!(link.attributes.borrow().get("href"), Some("/{{ 2 + 2 }}"));
+        assert_eq!(d.select_first("#prop .from-lua").unwrap().text_contents(), "{{ 1 + 1 }}!");
+    }
+
     #[test]
     fn basic_include() {
         let page = r#"
@@ -290,7 +1455,7 @@ mod tests {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         p.push("tests/components");
         let document = kuchikiki::parse_html().one(page);
-        let d = expand_template(document, &p, None).unwrap();
+        let d = expand_template(document, &p, None, &test_ctx()).unwrap();
         let text = d.select_first("span").unwrap().as_node().text_contents();
         assert_eq!(text, "included1");
         assert!(d.select_first("include").is_err());
@@ -315,7 +1480,7 @@ mod tests {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         p.push("tests/components");
         let document = kuchikiki::parse_html().one(page);
-        let d = expand_template(document, &p, None).unwrap();
+        let d = expand_template(document, &p, None, &test_ctx()).unwrap();
         let text = d.select_first("#inc1").unwrap().as_node().text_contents();
         assert_eq!(text, "included1");
         let text = d.select_first("#inc2").unwrap().as_node().text_contents();
@@ -345,7 +1510,8 @@ mod tests {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         p.push("tests/components");
         let document = kuchikiki::parse_html().one(page);
-        let d = execute_lua(expand_template(document, &p, None).unwrap()).unwrap();
+        let ctx = test_ctx();
+        let d = execute_lua(expand_template(document, &p, None, &ctx).unwrap(), &ctx).unwrap();
         let text = d.select_first("span").unwrap().as_node().text_contents();
         assert_eq!(text, "Test from Lua!\n");
         assert!(d.select_first("lua").is_err());
@@ -370,7 +1536,7 @@ mod tests {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         p.push("tests/components");
         let document = kuchikiki::parse_html().one(page);
-        let d = expand_template(document, &p, None).unwrap();
+        let d = expand_template(document, &p, None, &test_ctx()).unwrap();
         let text = d.select_first("span").unwrap().as_node().text_contents();
         assert_eq!(text, "included_twice");
         assert!(d.select_first("include").is_err());
@@ -395,7 +1561,7 @@ mod tests {
         let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         p.push("tests/components");
         let document = kuchikiki::parse_html().one(page);
-        let d = expand_template(document, &p, None).unwrap();
+        let d = expand_template(document, &p, None, &test_ctx()).unwrap();
         let text = d.select_first("#ta").unwrap().as_node().text_contents();
         assert_eq!(text, "element 1");
         let text = d.select_first("#tb").unwrap().as_node().text_contents();
@@ -416,7 +1582,7 @@ mod tests {
         p.push("tests/components");
         let ctx_name = QualName::new(None, ns!(html), LocalName::from("div"));
         let document = kuchikiki::parse_fragment(ctx_name, Vec::new()).one(page);
-        let d = expand_template(document, &p, None).unwrap();
+        let d = expand_template(document, &p, None, &test_ctx()).unwrap();
         let text = d
             .select_first("head")
             .unwrap()
@@ -432,9 +1598,295 @@ mod tests {
         assert!(d.select_first("includeelement").is_err());
     }
 
+    #[test]
+    fn control_flow() {
+        let page = r##"
+            <div>
+                <lua>
+                    items = { { name = "a", tags = { "x", "y" } }, { name = "b", tags = {} } }
+                    user = nil
+                </lua>
+                <ul id="items"><for each="item in items">
+                    <li class="{{ item.name }}">{{ item.name }}<for each="tag in item.tags"><b>{{ tag }}</b></for>
+                    <if cond="#item.tags == 0"><i>untagged</i></if>
+                    <lua>htmlua.print(item.name:upper())</lua></li>
+                </for></ul>
+                <p id="user"><if cond="user ~= nil">Hello {{ user }}</if>
+                <else>Anonymous</else></p>
+                <p id="pairs"><for each="key, value in { one = 1 }">{{ key }}={{ value }}</for></p>
+                <p id="scope"><lua>htmlua.print(tostring(item))</lua></p>
+            </div>"##;
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let d = execute_lua(document, &ctx).unwrap();
+        let items: Vec<_> = d.select("#items li").unwrap().collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].attributes.borrow().get("class"), Some("a"));
+        assert_eq!(items[0].text_contents().split_whitespace().collect::<Vec<_>>(), ["axy", "A"]);
+        assert_eq!(items[1].text_contents().split_whitespace().collect::<Vec<_>>(), ["b", "untagged", "B"]);
+        assert_eq!(d.select_first("#user").unwrap().text_contents().trim(), "Anonymous");
+        assert_eq!(d.select_first("#pairs").unwrap().text_contents(), "one=1");
+        assert_eq!(d.select_first("#scope").unwrap().text_contents(), "nil");
+        assert!(d.select_first("for").is_err() && d.select_first("if").is_err() && d.select_first("else").is_err());
+    }
+
+    #[test]
+    fn lua_runs_in_document_order() {
+        let page = r#"
+            <div>
+                <lua>n = 0 x = 1</lua>
+                <p id="before">{{ x }}</p>
+                <for each="i in { 1, 2 }"><lua>n = n + i</lua></for>
+                <if cond="n == 3"><lua>x = 2</lua></if>
+                <p id="after" lua:title="x">{{ x }}</p>
+                <span id="n"><lua>htmlua.print(n)</lua></span>
+            </div>"#;
+        let ctx = test_ctx();
+        let d = execute_lua(kuchikiki::parse_html().one(page), &ctx).unwrap();
+        assert_eq!(d.select_first("#before").unwrap().text_contents(), "1");
+        let after = d.select_first("#after").unwrap();
+        assert_eq!(after.text_contents(), "2");
+        assert_eq!(after.attributes.borrow().get("title"), Some("2"));
+        assert_eq!(d.select_first("#n").unwrap().text_contents(), "3");
+    }
+
+    #[test]
+    fn escaped_placeholders() {
+        let page = r#"
+            <div>
+                <lua>name = "Ada"</lua>
+                <p id="text">\{{ name }} is {{ name }}</p>
+                <a id="attr" title="\{{ name }}">x</a>
+            </div>"#;
+        let ctx = test_ctx();
+        let d = execute_lua(kuchikiki::parse_html().one(page), &ctx).unwrap();
+        assert_eq!(d.select_first("#text").unwrap().text_contents(), "{{ name }} is Ada");
+        assert_eq!(d.select_first("#attr").unwrap().attributes.borrow().get("title"), Some("{{ name }}"));
+    }
+
+    #[test]
+    fn lua_defined_tags() {
+        let page = r#"
+            <div>
+                <lua>
+                    htmlua.define_tag("alert", function(attrs, inner_html)
+                        return '<div class="alert ' .. attrs.type .. '">' .. inner_html .. '</div>'
+                    end)
+                    htmlua.define_tag("warn", function(_, inner_html)
+                        return '<alert type="warn">' .. inner_html .. '</alert>'
+                    end)
+                </lua>
+                <div id="info"><alert type="info"><b>Hi</b></alert></div>
+                <div id="warn"><warn>Careful</warn></div>
+            </div>"#;
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let d = expand_lua_tags(execute_lua(document, &ctx).unwrap(), &ctx).unwrap();
+        let info = d.select_first("#info .alert").unwrap();
+        assert_eq!(info.attributes.borrow().get("class"), Some("alert info"));
+        assert_eq!(info.as_node().select_first("b").unwrap().text_contents(), "Hi");
+        assert_eq!(d.select_first("#warn .warn").unwrap().text_contents(), "Careful");
+        assert!(d.select_first("alert").is_err());
+
+        let lua = &ctx.lua().unwrap().lua;
+        assert!(
+            lua.load(r#"htmlua.define_tag("Bad Tag", function() end)"#)
+                .exec()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn else_without_if() {
+        let document = kuchikiki::parse_html().one("<div><p>x</p><else>y</else></div>");
+        let ctx = test_ctx();
+        assert!(execute_lua(document, &ctx).is_err());
+    }
+
+    #[test]
+    fn include_props() {
+        let page = r#"
+            <!DOCTYPE html>
+            <html>
+            <body>
+                <div id="first"><include path="card.html" title="Hello" href="/x"></include></div>
+                <div id="second"><include path="card.html" title="World" href="/y"></include></div>
+            </body>
+            </html>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let d = expand_template(document, &p, None, &ctx).unwrap();
+        // Placeholders that aren't props are left for the lua stage.
+        assert_eq!(d.select_first("#first .unknown").unwrap().text_contents(), "{{unknown}}");
+        let d = execute_lua(d, &ctx).unwrap();
+        let link = d.select_first("#first a").unwrap();
+        assert_eq!(link.attributes.borrow().get("href"), Some("/x"));
+        assert_eq!(link.text_contents(), "Hello");
+        assert_eq!(d.select_first("#first .from-lua").unwrap().text_contents(), "Hello!");
+        let link = d.select_first("#second a").unwrap();
+        assert_eq!(link.attributes.borrow().get("href"), Some("/y"));
+        assert_eq!(link.text_contents(), "World");
+        assert_eq!(d.select_first("#second .from-lua").unwrap().text_contents(), "World!");
+    }
+
+    #[test]
+    fn lua_scopes() {
+        let page = r#"
+            <div>
+                <div id="a"><include path="scoped.html" name="a"></include></div>
+                <div id="b"><include path="scoped.html" name="b"></include></div>
+                <lua scope="local">x = 1</lua>
+                <span id="page"><lua>
+                    htmlua.print(tostring(items) .. " " .. tostring(x) .. " " .. htmlua.shared.seen)
+                </lua></span>
+            </div>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let d = execute_lua(expand_template(document, &p, None, &ctx).unwrap(), &ctx).unwrap();
+        assert_eq!(d.select_first("#a .items").unwrap().text_contents(), "a");
+        assert_eq!(d.select_first("#b .items").unwrap().text_contents(), "b");
+        assert_eq!(d.select_first("#page").unwrap().text_contents(), "nil nil ab");
+    }
+
+    #[test]
+    fn component_scope_in_loop() {
+        let page = r#"<div><include path="looped.html" name="a"></include></div>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let d = expand_template(document, &p, None, &ctx).unwrap();
+        let d = execute_lua(d, &ctx).unwrap();
+        let items: Vec<_> = d.select(".looped b").unwrap().map(|b| b.text_contents()).collect();
+        assert_eq!(items, ["a1=1", "a2=3"]);
+        let globals = ctx.lua().unwrap().lua.globals();
+        assert!(globals.get::<mlua::Value>("total").unwrap().is_nil());
+        assert!(globals.get::<mlua::Value>("label").unwrap().is_nil());
+    }
+
+    #[test]
+    fn lua_error_location() {
+        let page = r#"<div><include path="broken.html"></include></div>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let err = execute_lua(expand_template(document, &p, None, &ctx).unwrap(), &ctx).unwrap_err();
+        let RenderError::LuaBlockError(err) = err else {
+            panic!("expected a Lua block error, got {err}");
+        };
+        assert_eq!(err.source_file, Some(p.join("broken.html").canonicalize().unwrap()));
+        assert_eq!(err.block, Some(2));
+        assert_eq!(err.block_line, Some(4));
+        assert_eq!(err.line, Some(6));
+        assert!(
+            err.message
+                .contains("broken.html:4:3: attempt to call a nil value (global 'missing_function')")
+        );
+        assert!(err.traceback.unwrap().contains("broken.html:4:3: in main chunk"));
+    }
+
+    #[test]
+    fn lua_error_chunk_name() {
+        let mut components = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        components.push("tests/components");
+        let mut config = Config::default();
+        config.paths.components.clone_from(&components);
+        let ctx = RenderContext::new(Request::default(), Renderer::new(config).unwrap());
+        let document = kuchikiki::parse_html().one(r#"<div><include path="broken.html"></include></div>"#);
+        let err = execute_lua(expand_template(document, &components, None, &ctx).unwrap(), &ctx).unwrap_err();
+        // The chunk is named after the file, relative to its root, and the line its block starts on.
+        assert!(
+            err.to_string()
+                .contains("components/broken.html:4:3: attempt to call a nil value (global 'missing_function')"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn pre_phase_lua() {
+        let page = r#"
+            <div>
+                <div id="list"><lua phase="pre">
+                    titles = { "One", "Two" }
+                    for _, t in ipairs(titles) do
+                        htmlua.print_html('<include path="card.html" title="' .. t .. '" href="/' .. t .. '"></include>')
+                    end
+                </lua></div>
+                <span id="count"><lua>htmlua.print(#titles)</lua></span>
+                <span id="escaped"><lua phase="pre">htmlua.print('<b title="x">&amp;</b>')</lua></span>
+                <span id="raw"><lua phase="pre" mode="html">htmlua.print("<b>bold</b>")</lua></span>
+            </div>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let d = execute_lua(expand_template(document, &p, None, &ctx).unwrap(), &ctx).unwrap();
+        let cards: Vec<_> = d
+            .select("#list .from-lua")
+            .unwrap()
+            .map(|c| c.text_contents())
+            .collect();
+        assert_eq!(cards, ["One!", "Two!"]);
+        assert_eq!(d.select_first("#list a").unwrap().attributes.borrow().get("href"), Some("/One"));
+        assert_eq!(d.select_first("#count").unwrap().text_contents(), "2");
+        let escaped = d.select_first("#escaped").unwrap();
+        assert!(escaped.as_node().select_first("b").is_err());
+        assert_eq!(escaped.text_contents(), r#"<b title="x">&amp;</b>"#);
+        assert_eq!(d.select_first("#raw b").unwrap().text_contents(), "bold");
+    }
+
+    #[test]
+    fn include_cycle() {
+        let page = r#"<div><include path="cycle_a.html"></include></div>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let err = expand_template(document, &p, None, &test_ctx()).unwrap_err();
+        assert_eq!(err.to_string(), "Include error: cycle detected: cycle_a.html → cycle_b.html → cycle_a.html");
+    }
+
+    #[test]
+    fn include_max_depth() {
+        let page = r#"<div><include path="comp2_level1.html"></include></div>"#;
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        let document = kuchikiki::parse_html().one(page);
+        let ctx = test_ctx();
+        let err = expand_template_with_chain(document, &p, None, &ctx, &mut Vec::new(), 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Include error: maximum depth of 1 exceeded: comp2_level1.html → comp2_level2.html"
+        );
+    }
+
+    #[test]
+    fn include_outside_components() {
+        let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        p.push("tests/components");
+        for path in [
+            "../lua_modules/blog/init.lua",
+            "/etc/passwd",
+            "multi1_1.html/../../../Cargo.toml",
+        ] {
+            let page = format!(r#"<div><include path="{path}"></include></div>"#);
+            let document = kuchikiki::parse_html().one(page);
+            let err = expand_template(document, &p, None, &test_ctx()).unwrap_err();
+            assert!(matches!(err, RenderError::Forbidden(_)), "{path}: {err}");
+        }
+
+        let document = kuchikiki::parse_html().one(r#"<div><include path="missing.html"></include></div>"#);
+        let err = expand_template(document, &p, None, &test_ctx()).unwrap_err();
+        assert!(matches!(err, RenderError::NotFound(_)));
+    }
+
     #[test]
     fn footnotes() {
-        let page = r"
+        let page = r#"
             <!DOCTYPE html>
             <html>
             <head>
@@ -446,12 +1898,13 @@ mod tests {
                     <p>asdf<footnote>um actually</footnote></p>
                     <p>asdf</p>
                     <p>asdf<footnote>no</footnote></p>
+                    <p>asdf<footnote>&lt;img src=x onerror="alert(1)"&gt; &amp;amp;</footnote></p>
                 </div>
                 <div>
                 <footnotecontainer></footnotecontainer>
                 </div>
                 </body>
-                </html>";
+                </html>"#;
         let document = kuchikiki::parse_html().one(page);
         let d = generate_footnotes(document).unwrap();
         let sup1 = d.select_first("#ft-sup-1").unwrap();
@@ -468,6 +1921,13 @@ mod tests {
         let ft_text_2 = d.select_first("#ft-text-2").unwrap();
         assert!(ft_text_2.text_contents().contains("no"));
 
+        let sup3 = d.select_first("#ft-sup-3").unwrap();
+        let text = r#"<img src=x onerror="alert(1)"> &amp;"#;
+        assert_eq!(sup3.attributes.borrow().get("title").unwrap(), text);
+        let ft_text_3 = d.select_first("#ft-text-3").unwrap();
+        assert!(ft_text_3.text_contents().ends_with(text));
+        assert!(d.select_first("img").is_err());
+
         assert!(d.select_first("footnote").is_err());
         assert!(d.select_first("footnotecontainer").is_err());
     }
@@ -521,7 +1981,7 @@ fn main() {
             </body>
             </html>"#;
         let document = kuchikiki::parse_html().one(page);
-        let d = process_syntax_highlighting(document).unwrap();
+        let d = highlight_document(document, &test_renderer()).unwrap();
         assert!(d.select_first("syntaxhighlight").is_err());
         assert!(d.select_first("pre").is_ok());
         assert!(d.select_first("code").is_ok());
@@ -529,6 +1989,190 @@ fn main() {
         let attrs = pre.attributes.borrow();
         assert_eq!(attrs.get("data-lang"), Some("rust"));
         assert!(attrs.get("class").unwrap().contains("syntax-highlight"));
+        // Without line options the code is kept as written, blank first and last lines included.
+        let code = pre.text_contents();
+        assert!(code.starts_with("\nfn main() {"));
+        assert!(code.ends_with("}\n                "));
+    }
+
+    #[test]
+    fn syntax_highlighting_unknown_theme() {
+        let page = r#"<div><syntaxhighlight lang="rust" theme="typo">let x = 1;</syntaxhighlight></div>"#;
+        let document = kuchikiki::parse_html().one(page);
+        let d = highlight_document(document, &test_renderer()).unwrap();
+        assert!(d.select_first("syntaxhighlight").is_err());
+        assert_eq!(d.select_first("pre code").unwrap().text_contents(), "let x = 1;");
+    }
+
+    #[test]
+    fn syntax_highlighting_lines() {
+        let page = r#"<div>
+            <syntaxhighlight lang="rust" linenos start="120" highlight="121-122, 124">
+fn a() {}
+fn b() {}
+fn c() {}
+fn d() {}
+fn e() {}
+            </syntaxhighlight>
+        </div>"#;
+        let d = highlight_document(kuchikiki::parse_html().one(page), &test_renderer()).unwrap();
+        let lines: Vec<_> = d.select("pre code > .line").unwrap().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0].attributes.borrow().get("data-line"), Some("120"));
+        assert_eq!(lines[4].text_contents(), "124fn e() {}\n");
+        let highlighted: Vec<_> = d
+            .select(".line.highlighted")
+            .unwrap()
+            .map(|line| line.attributes.borrow().get("data-line").unwrap().to_string())
+            .collect();
+        assert_eq!(highlighted, ["121", "122", "124"]);
+
+        let page = r#"<syntaxhighlight highlight="5-3">x</syntaxhighlight>"#;
+        assert!(highlight_document(kuchikiki::parse_html().one(page), &test_renderer()).is_err());
+        let page = r#"<syntaxhighlight start="-1">x</syntaxhighlight>"#;
+        assert!(highlight_document(kuchikiki::parse_html().one(page), &test_renderer()).is_err());
+    }
+
+    #[test]
+    fn syntax_highlighting_snippets() {
+        let mut config = Config::default();
+        config.paths.snippets = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/snippets");
+        let renderer = Renderer::new(config).unwrap();
+        let highlight = |page: &str| highlight_document(kuchikiki::parse_html().one(page), &renderer);
+
+        let d = highlight(r#"<syntaxhighlight src="example.rs" region="point"></syntaxhighlight>"#).unwrap();
+        let pre = d.select_first("pre").unwrap();
+        assert_eq!(pre.attributes.borrow().get("data-lang"), Some("rs"));
+        assert_eq!(pre.text_contents(), "struct Point {\n    x: i32,\n    y: i32,\n}\n");
+        let first = d.select_first(".line").unwrap();
+        assert_eq!(first.attributes.borrow().get("data-line"), Some("4"));
+
+        let d = highlight(r#"<syntaxhighlight src="example.rs" lines="11-13" start="1"></syntaxhighlight>"#).unwrap();
+        assert!(
+            d.select_first("pre")
+                .unwrap()
+                .text_contents()
+                .starts_with("    fn fmt(&self")
+        );
+        assert_eq!(d.select("pre .line").unwrap().count(), 3);
+        assert_eq!(d.select_first(".line").unwrap().attributes.borrow().get("data-line"), Some("1"));
+
+        assert!(matches!(
+            highlight(r#"<syntaxhighlight src="example.rs" lines="10-99"></syntaxhighlight>"#),
+            Err(RenderError::IncludeError(_))
+        ));
+        let err = highlight(r#"<syntaxhighlight src="example.rs" region="missing"></syntaxhighlight>"#).unwrap_err();
+        assert!(matches!(err, RenderError::IncludeError(_)));
+        assert_eq!(err.to_string(), "Include error: No region 'missing' with an endregion in example.rs");
+        for src in ["missing.rs", "."] {
+            let err = highlight(&format!(r#"<syntaxhighlight src="{src}"></syntaxhighlight>"#)).unwrap_err();
+            assert!(matches!(err, RenderError::NotFound(_)), "{src}: {err}");
+            assert_eq!(err.status(), 404);
+        }
+        assert!(matches!(
+            highlight(r#"<syntaxhighlight src="../components/card.html"></syntaxhighlight>"#),
+            Err(RenderError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn classed_lines_are_balanced() {
+        let code = "/* a\nb */\nfn main() {}\n";
+        let lines = highlight_lines(test_renderer().syntaxes(), LineStyle::Classed, code, "rs").unwrap();
+        assert_eq!(lines.len(), 3);
+        for line in &lines {
+            assert_eq!(str::matches(line, "<span").count(), str::matches(line, "</span>").count(), "{line}");
+        }
+        assert!(lines[1].starts_with(r#"<span class="hl-source hl-rust"><span class="hl-comment hl-block hl-rust">"#));
+    }
+
+    #[test]
+    fn request_table() {
+        let mut request = Request::new("post", "/form.html");
+        request.query_string = "page=2&q=hello%20world".to_string();
+        request.add_header("Content-Type", "application/x-www-form-urlencoded");
+        request.add_header("Cookie", "session=abc123; theme=dark");
+        request.body = "name=htmlua&lang=lua".to_string();
+
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &RenderContext::new(request, test_renderer())).unwrap();
+        let code = r#"
+            local r = htmlua.request
+            htmlua.print(table.concat({
+                r.method, r.path, r.query.page, r.query.q, r.form.name, r.cookies.theme,
+                r.headers["content-type"],
+            }, "|"))
+        "#;
+        lua.load(code).exec().unwrap();
+        assert_eq!(
+            stdout.borrow().html.as_str(),
+            "POST|/form.html|2|hello world|htmlua|dark|application/x-www-form-urlencoded"
+        );
+        assert!(lua.load("htmlua.request.method = 'GET'").exec().is_err());
+        assert!(lua.load("htmlua.request.query.page = '3'").exec().is_err());
+    }
+
+    #[test]
+    fn response_table() {
+        let ctx = test_ctx();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &ctx).unwrap();
+        let code = r#"
+            htmlua.response.set_status(201)
+            htmlua.response.set_header("X-Test", "a")
+            htmlua.response.set_header("x-test", "b")
+            htmlua.response.set_cookie("session", "abc", { path = "/", http_only = true, max_age = 60 })
+            htmlua.response.json({ ok = true })
+        "#;
+        lua.load(code).exec().unwrap();
+        let response = ctx.response.borrow();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("X-Test"), Some("b"));
+        assert_eq!(
+            response
+                .headers
+                .iter()
+                .filter(|(k, _)| k == "x-test" || k == "X-Test")
+                .count(),
+            1
+        );
+        assert_eq!(response.header("Set-Cookie"), Some("session=abc; Path=/; Max-Age=60; HttpOnly"));
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(response.body, r#"{"ok":true}"#);
+    }
+
+    #[test]
+    fn response_redirect() {
+        let ctx = test_ctx();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &ctx).unwrap();
+        lua.load(r#"htmlua.response.redirect("/login")"#).exec().unwrap();
+        let response = ctx.response.borrow();
+        assert_eq!(response.status, 302);
+        assert_eq!(response.header("Location"), Some("/login"));
+        assert!(response.is_redirect());
+    }
+
+    #[test]
+    fn response_rejects_header_injection() {
+        let mut request = Request::new("GET", "/");
+        request.query_string = "next=%2Fa%0d%0aSet-Cookie:%20evil=1".to_string();
+        let ctx = RenderContext::new(request, test_renderer());
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &ctx).unwrap();
+        for code in [
+            "htmlua.response.redirect(htmlua.request.query.next)",
+            r#"htmlua.response.set_header("X-Next", htmlua.request.query.next)"#,
+            r#"htmlua.response.add_header("X Bad", "1")"#,
+            r#"htmlua.response.set_cookie("a;b", "1")"#,
+            r#"htmlua.response.set_cookie("a", "1; Domain=evil.example")"#,
+            r#"htmlua.response.set_cookie("a", "1", { path = "/; Secure" })"#,
+        ] {
+            assert!(lua.load(code).exec().is_err(), "{code}");
+        }
+        let response = ctx.response.borrow();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.len(), 1);
     }
 
     static SERVER_POOL: ServerPool = ServerPool::new(2);
@@ -541,11 +2185,11 @@ fn main() {
             Expectation::matching(request::method_path("GET", "/test/1")).respond_with(status_code(200).body("ret")),
         );
 
-        let stdout = Rc::new(RefCell::new(String::new()));
-        let lua = build_lua_with_stdout(&stdout).unwrap();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &test_ctx()).unwrap();
         let code = format!("htmlua.print(htmlua.http.get(\"{}\").body)", server.url("/test/1"));
         lua.load(code).exec().unwrap();
-        assert_eq!(stdout.borrow().as_str(), "ret");
+        assert_eq!(stdout.borrow().html.as_str(), "ret");
     }
 
     #[test]
@@ -556,11 +2200,11 @@ fn main() {
             Expectation::matching(request::method_path("POST", "/test/1")).respond_with(status_code(200).body("ret")),
         );
 
-        let stdout = Rc::new(RefCell::new(String::new()));
-        let lua = build_lua_with_stdout(&stdout).unwrap();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &test_ctx()).unwrap();
         let code = format!("htmlua.print(htmlua.http.post(\"{}\").body)", server.url("/test/1"));
         lua.load(code).exec().unwrap();
-        assert_eq!(stdout.borrow().as_str(), "ret");
+        assert_eq!(stdout.borrow().html.as_str(), "ret");
     }
 
     #[test]
@@ -572,8 +2216,8 @@ fn main() {
                 .respond_with(status_code(200).body("ret")),
         );
 
-        let stdout = Rc::new(RefCell::new(String::new()));
-        let lua = build_lua_with_stdout(&stdout).unwrap();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &test_ctx()).unwrap();
         let code = format!(
             "
                 data = {{}}
@@ -583,7 +2227,7 @@ fn main() {
             server.url("/test/1")
         );
         lua.load(code).exec().unwrap();
-        assert_eq!(stdout.borrow().as_str(), "ret");
+        assert_eq!(stdout.borrow().html.as_str(), "ret");
     }
 
     #[test]
@@ -595,8 +2239,8 @@ fn main() {
                 .respond_with(status_code(200).body("ret")),
         );
 
-        let stdout = Rc::new(RefCell::new(String::new()));
-        let lua = build_lua_with_stdout(&stdout).unwrap();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &test_ctx()).unwrap();
         let code = format!(
             "
                 data = {{}}
@@ -606,7 +2250,7 @@ fn main() {
             server.url("/test/1")
         );
         lua.load(code).exec().unwrap();
-        assert_eq!(stdout.borrow().as_str(), "ret");
+        assert_eq!(stdout.borrow().html.as_str(), "ret");
     }
 
     #[test]
@@ -618,8 +2262,8 @@ fn main() {
                 .respond_with(status_code(200).body("ret")),
         );
 
-        let stdout = Rc::new(RefCell::new(String::new()));
-        let lua = build_lua_with_stdout(&stdout).unwrap();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &test_ctx()).unwrap();
         let code = format!(
             "
                 data = {{}}
@@ -629,7 +2273,7 @@ fn main() {
             server.url("/test/1")
         );
         lua.load(code).exec().unwrap();
-        assert_eq!(stdout.borrow().as_str(), "ret");
+        assert_eq!(stdout.borrow().html.as_str(), "ret");
     }
 
     #[test]
@@ -646,8 +2290,8 @@ fn main() {
             .respond_with(status_code(200).body("ret")),
         );
 
-        let stdout = Rc::new(RefCell::new(String::new()));
-        let lua = build_lua_with_stdout(&stdout).unwrap();
+        let stdout = Rc::new(RefCell::new(LuaOutput::default()));
+        let lua = build_lua_with_stdout(&stdout, &test_ctx()).unwrap();
         let code = format!(
             "
                 req = {{}}
@@ -665,6 +2309,6 @@ fn main() {
             server.url("/test/1")
         );
         lua.load(code).exec().unwrap();
-        assert_eq!(stdout.borrow().as_str(), "ret");
+        assert_eq!(stdout.borrow().html.as_str(), "ret");
     }
 }
@@ -0,0 +1,534 @@
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use kuchikiki::NodeRef;
+use syntect::{
+    dumps,
+    highlighting::{Theme, ThemeSet},
+    html::css_for_theme_with_class_style,
+    parsing::SyntaxSet,
+};
+
+use crate::{
+    config::{Config, SyntaxConfig},
+    dev_overlay::render_error_overlay,
+    error::{RenderError, Result},
+    helpers::{escape_html, parse_document, read_doc_from_file, resolve_within},
+    pipeline::{RenderPipeline, TagProcessor, TagRegistry},
+    render::{CLASS_STYLE, RenderContext, custom_theme_paths, load_custom_themes},
+    request::Request,
+    response::Response,
+    serve::get_config,
+};
+
+/// Serves the stylesheet for `css_classes` mode of each theme, e.g. `/_htmlua/theme/InspiredGitHub.css`.
+const THEME_CSS_ROUTE: &str = "/_htmlua/theme/";
+
+static GLOBAL_RENDERER: OnceLock<Renderer> = OnceLock::new();
+static DEFAULT_SYNTAXES: OnceLock<Arc<SyntaxSet>> = OnceLock::new();
+static DEFAULT_THEMES: OnceLock<Arc<ThemeSet>> = OnceLock::new();
+
+/// The names of the themes in `ThemeSet::load_defaults`, so a theme name can be checked without loading them. Kept
+/// in step with syntect by the `bundled_theme_names` test.
+const BUNDLED_THEMES: [&str; 7] = [
+    "InspiredGitHub",
+    "Solarized (dark)",
+    "Solarized (light)",
+    "base16-eighties.dark",
+    "base16-mocha.dark",
+    "base16-ocean.dark",
+    "base16-ocean.light",
+];
+
+/// Renders pages with one configuration, its syntax highlighting assets and its pipeline, including any custom tags
+/// registered on the builder, e.g. `Renderer::builder().tag(Mermaid).build()`. Build one per configuration and share
+/// it; cloning is cheap.
+#[derive(Clone)]
+pub struct Renderer {
+    config: Arc<Config>,
+    highlighting: Arc<HighlightAssets>,
+    pipeline: Arc<RenderPipeline>,
+}
+
+/// Syntax highlighting assets, loaded on first use and shared by the clones of a renderer. The bundled syntect sets
+/// are loaded once per process.
+#[derive(Default)]
+struct HighlightAssets {
+    syntaxes: OnceLock<Arc<SyntaxSet>>,
+    themes: OnceLock<Arc<ThemeSet>>,
+}
+
+#[derive(Default)]
+pub struct RendererBuilder {
+    config: Option<Config>,
+    tags: Vec<Box<dyn TagProcessor>>,
+}
+
+impl RendererBuilder {
+    /// Sets the configuration; without one the builder uses the process-wide config from `get_config`.
+    #[must_use]
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Registers a custom tag. Registering the same tag again replaces the earlier processor.
+    #[must_use]
+    pub fn tag(mut self, processor: impl TagProcessor + 'static) -> Self {
+        self.tags.retain(|t| t.tag() != processor.tag());
+        self.tags.push(Box::new(processor));
+        self
+    }
+
+    /// Builds the pipeline and checks `syntax_highlighting.default_theme`. Fails on an unknown pipeline stage or
+    /// default theme.
+    pub fn build(self) -> Result<Renderer> {
+        let config = self.config.unwrap_or_else(|| get_config().clone());
+        let tags: TagRegistry = Arc::new(self.tags);
+        let pipeline = RenderPipeline::from_config(&config, &tags)?;
+        let renderer = Renderer {
+            config: Arc::new(config),
+            highlighting: Arc::default(),
+            pipeline: Arc::new(pipeline),
+        };
+        let default_theme = &renderer.config.syntax_highlighting.default_theme;
+        if !renderer.has_theme(default_theme) {
+            return Err(RenderError::ConfigError(format!(
+                "Unknown syntax_highlighting.default_theme '{default_theme}', available themes: {}",
+                renderer.theme_names().join(", ")
+            )));
+        }
+        Ok(renderer)
+    }
+}
+
+impl Renderer {
+    pub fn new(config: Config) -> Result<Self> { Self::builder().config(config).build() }
+
+    #[must_use]
+    pub fn builder() -> RendererBuilder { RendererBuilder::default() }
+
+    /// The renderer for the process-wide config, used by the free functions such as `serve_content`. An invalid
+    /// pipeline or default theme in the config is replaced by the default one.
+    ///
+    /// # Panics
+    ///
+    /// If the default pipeline or theme fails to load, which would be a bug.
+    pub fn global() -> &'static Renderer { GLOBAL_RENDERER.get_or_init(|| Self::with_fallbacks(get_config().clone())) }
+
+    /// Builds a renderer for `config`, replacing only the pipeline or default theme if it is invalid.
+    fn with_fallbacks(mut config: Config) -> Renderer {
+        let defaults = Config::default();
+        if let Err(e) = RenderPipeline::from_config(&config, &TagRegistry::default()) {
+            eprintln!("Warning: {e}");
+            eprintln!("Using the default pipeline");
+            config.templates.pipeline = defaults.templates.pipeline;
+        }
+        Renderer::new(config.clone()).unwrap_or_else(|e| {
+            let default_theme = defaults.syntax_highlighting.default_theme;
+            eprintln!("Warning: {e}");
+            eprintln!("Using the default theme '{default_theme}'");
+            config.syntax_highlighting.default_theme = default_theme;
+            Renderer::new(config).expect("the default pipeline and theme are valid")
+        })
+    }
+
+    #[must_use]
+    pub fn config(&self) -> &Config { &self.config }
+
+    /// The syntaxes from `syntax_highlighting.syntax_dump`, or the bundled ones.
+    #[must_use]
+    pub fn syntaxes(&self) -> &SyntaxSet {
+        self.highlighting
+            .syntaxes
+            .get_or_init(|| load_syntaxes(&self.config.syntax_highlighting))
+    }
+
+    /// The themes from `syntax_highlighting.theme_dump`, or the bundled ones, plus the custom themes if enabled.
+    #[must_use]
+    pub fn themes(&self) -> &ThemeSet { self.highlighting.themes.get_or_init(|| load_themes(&self.config)) }
+
+    /// Whether `name` is one of `themes`. Bundled themes are known by name and a custom theme is found the way
+    /// `load_custom_themes` finds it, then parsed on its own, so only a theme dump is loaded in full for this.
+    fn has_theme(&self, name: &str) -> bool {
+        let config = &self.config;
+        if config.syntax_highlighting.theme_dump.is_some() {
+            return self.themes().themes.contains_key(name);
+        }
+        BUNDLED_THEMES.contains(&name)
+            || config.syntax_highlighting.load_custom_themes
+                && custom_theme_paths(&config.paths.themes)
+                    .iter()
+                    .any(|(custom, path)| custom == name && ThemeSet::get_theme(path).is_ok())
+    }
+
+    /// The names of the available themes, bundled and custom, in sorted order.
+    #[must_use]
+    pub fn theme_names(&self) -> Vec<&str> { self.themes().themes.keys().map(String::as_str).collect() }
+
+    /// Looks up a theme by name; the error lists the available themes.
+    pub fn theme(&self, name: &str) -> Result<&Theme> {
+        self.themes().themes.get(name).ok_or_else(|| {
+            RenderError::HighlightError(format!(
+                "Unknown theme '{name}', available themes: {}",
+                self.theme_names().join(", ")
+            ))
+        })
+    }
+
+    /// The stylesheet for the classes highlighted code gets in `css_classes` mode, coloured by the theme `name`. Wrap
+    /// two of them in `prefers-color-scheme` media queries for dark and light variants.
+    pub fn theme_css(&self, name: &str) -> Result<String> {
+        css_for_theme_with_class_style(self.theme(name)?, CLASS_STYLE)
+            .map_err(|e| RenderError::HighlightError(e.to_string()))
+    }
+
+    /// Writes the syntaxes and themes of this renderer, custom themes included, as dumps for
+    /// `syntax_highlighting.syntax_dump` and `theme_dump`.
+    pub fn dump_highlighting_assets(&self, syntax_dump: &Path, theme_dump: &Path) -> Result<()> {
+        dumps::dump_to_file(self.syntaxes(), syntax_dump)
+            .and_then(|()| dumps::dump_to_file(self.themes(), theme_dump))
+            .map_err(|e| RenderError::HighlightError(format!("Failed to write highlighting dump: {e}")))
+    }
+
+    /// Renders the page `request` points to under the pages directory, or serves a theme stylesheet.
+    pub fn serve(&self, request: &Request) -> Result<Response> {
+        if let Some(name) = request
+            .path
+            .strip_prefix(THEME_CSS_ROUTE)
+            .and_then(|file| file.strip_suffix(".css"))
+        {
+            return self.serve_theme_css(name, request);
+        }
+        let pages = &self.config.paths.pages;
+        let mut page_path = resolve_within(pages, Path::new(request.path.trim_start_matches('/')))?;
+        if page_path.is_dir() {
+            page_path = resolve_within(&page_path, Path::new("index.html"))?;
+        }
+        self.render_file(&page_path, request)
+    }
+
+    fn serve_theme_css(&self, name: &str, request: &Request) -> Result<Response> {
+        // The frontends pass the path already percent-decoded, e.g. `/_htmlua/theme/Solarized (dark).css`.
+        let body = self
+            .theme_css(name)
+            .map_err(|_| RenderError::NotFound(PathBuf::from(&request.path)))?;
+        Ok(Response {
+            headers: vec![("Content-Type".to_string(), "text/css; charset=utf-8".to_string())],
+            body,
+            ..Response::default()
+        })
+    }
+
+    /// Renders the page at `path`, which is read as is rather than resolved within the pages directory.
+    pub fn render_file(&self, path: &Path, request: &Request) -> Result<Response> {
+        let document = match read_doc_from_file(path) {
+            Ok(document) => document,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(RenderError::NotFound(path.to_path_buf())),
+            Err(e) => return Err(e.into()),
+        };
+        self.render_document(&document, request)
+    }
+
+    /// Renders a page given as source. Its `<lua>` blocks have no file to point errors at.
+    pub fn render_str(&self, html: &str, request: &Request) -> Result<Response> {
+        self.render_document(&parse_document(html), request)
+    }
+
+    fn render_document(&self, document: &NodeRef, request: &Request) -> Result<Response> {
+        let root = document
+            .select_first("html")
+            .map_err(|()| RenderError::ParseError("Page has no html root".to_string()))?;
+        let ctx = RenderContext::new(request.clone(), self.clone());
+        let rendered_doc = self.pipeline.run(root.as_node().to_owned(), &ctx)?;
+        let mut response = ctx.response.take();
+        // A body set from Lua (e.g. `htmlua.response.json`) replaces the rendered page.
+        if response.body.is_empty() && !response.is_redirect() {
+            response.body = rendered_doc.to_string();
+        }
+        Ok(response)
+    }
+
+    /// Builds the response for a failed render from the error page configured for its kind, or from the developer
+    /// overlay in dev mode.
+    #[must_use]
+    pub fn error_response(&self, error: &RenderError) -> Response {
+        let mut response = Response {
+            status: error.status(),
+            ..Response::default()
+        };
+        if self.config.dev_mode {
+            response.body = render_error_overlay(error, self);
+            return response;
+        }
+
+        let pages = &self.config.error_pages;
+        let page = match error {
+            RenderError::NotFound(_) => pages.not_found.as_ref(),
+            RenderError::Forbidden(_) => pages.forbidden.as_ref(),
+            RenderError::LuaError(_) | RenderError::LuaBlockError(_) => pages.lua_error.as_ref(),
+            RenderError::IncludeError(_) => pages.include_error.as_ref(),
+            RenderError::ParseError(_) => pages.parse_error.as_ref(),
+            RenderError::ConfigError(_) | RenderError::HighlightError(_) | RenderError::Io(_) => None,
+        }
+        .or(pages.default.as_ref());
+
+        response.body = match page.map(fs::read_to_string) {
+            Some(Ok(body)) => body,
+            Some(Err(e)) => {
+                eprintln!("Warning: Failed to read error page: {e}");
+                default_error_page(&response)
+            }
+            None => default_error_page(&response),
+        };
+        response
+    }
+}
+
+impl fmt::Debug for Renderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Renderer")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+fn load_syntaxes(config: &SyntaxConfig) -> Arc<SyntaxSet> {
+    if let Some(path) = &config.syntax_dump {
+        match dumps::from_dump_file(path) {
+            Ok(syntaxes) => return Arc::new(syntaxes),
+            Err(e) => eprintln!("Warning: Failed to load syntax dump {}: {e}", path.display()),
+        }
+    }
+    DEFAULT_SYNTAXES
+        .get_or_init(|| Arc::new(SyntaxSet::load_defaults_newlines()))
+        .clone()
+}
+
+fn load_themes(config: &Config) -> Arc<ThemeSet> {
+    let mut themes = None;
+    if let Some(path) = &config.syntax_highlighting.theme_dump {
+        match dumps::from_dump_file(path) {
+            Ok(dump) => themes = Some(Arc::new(dump)),
+            Err(e) => eprintln!("Warning: Failed to load theme dump {}: {e}", path.display()),
+        }
+    }
+    let themes = themes.unwrap_or_else(|| {
+        DEFAULT_THEMES
+            .get_or_init(|| Arc::new(ThemeSet::load_defaults()))
+            .clone()
+    });
+    if !config.syntax_highlighting.load_custom_themes || !config.paths.themes.is_dir() {
+        return themes;
+    }
+    // `ThemeSet` isn't `Clone`, so the custom themes go into a copy of its map.
+    let mut with_custom = ThemeSet {
+        themes: themes.themes.clone(),
+    };
+    load_custom_themes(&mut with_custom, &config.paths.themes);
+    Arc::new(with_custom)
+}
+
+fn default_error_page(response: &Response) -> String {
+    let title = escape_html(&format!("{} {}", response.status, response.reason_phrase()));
+    format!("<!DOCTYPE html>\n<html><head><title>{title}</title></head><body><h1>{title}</h1></body></html>\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+        config.paths.components = root.join("components");
+        config.paths.pages = root.join("components");
+        config
+    }
+
+    #[test]
+    fn renderers_keep_their_own_config() {
+        let page = r#"<div><include path="card.html" title="Hi" href="/"></include><markdown># Title</markdown></div>"#;
+        let request = Request::new("GET", "/");
+        let full = Renderer::new(test_config()).unwrap();
+        let body = full.render_str(page, &request).unwrap().body;
+        assert!(body.contains("<h1>Title</h1>"));
+        assert!(body.contains(r#"<span class="from-lua">Hi!</span>"#));
+
+        let mut config = test_config();
+        config.templates.pipeline = vec!["include".to_string()];
+        let include_only = Renderer::new(config).unwrap();
+        let body = include_only.render_str(page, &request).unwrap().body;
+        assert!(body.contains("<markdown># Title</markdown>"));
+        assert!(body.contains("<lua>"));
+        assert!(!body.contains("data-htmlua-"));
+    }
+
+    #[test]
+    fn render_file_and_serve() {
+        let renderer = Renderer::new(test_config()).unwrap();
+        let request = Request::new("GET", "/comp1.html");
+        let served = renderer.serve(&request).unwrap().body;
+        let path = renderer.config().paths.components.join("comp1.html");
+        assert_eq!(renderer.render_file(&path, &request).unwrap().body, served);
+        assert!(matches!(
+            renderer.render_file(Path::new("/nonexistent/page.html"), &request),
+            Err(RenderError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn error_status_codes() {
+        let renderer = Renderer::new(test_config()).unwrap();
+        let response = renderer.error_response(&RenderError::NotFound(PathBuf::from("missing.html")));
+        assert_eq!(response.status, 404);
+        assert!(response.body.contains("404 Not Found"));
+        let response = renderer.error_response(&RenderError::Forbidden(PathBuf::from("../x")));
+        assert_eq!(response.status, 403);
+        assert_eq!(
+            renderer
+                .error_response(&RenderError::LuaError("boom".to_string()))
+                .status,
+            500
+        );
+    }
+
+    #[test]
+    fn error_response_follows_dev_mode() {
+        let mut config = test_config();
+        config.dev_mode = true;
+        let error = RenderError::LuaError("boom".to_string());
+        let dev = Renderer::new(config).unwrap().error_response(&error);
+        assert!(dev.body.contains("boom"));
+        let production = Renderer::new(test_config()).unwrap().error_response(&error);
+        assert!(!production.body.contains("boom"));
+        assert_eq!(production.status, 500);
+    }
+
+    #[test]
+    fn highlighting_assets_from_dumps() {
+        let dir = std::env::temp_dir().join(format!("htmlua-dumps-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Small sets keep the test fast; compressing the bundled ones takes over a minute in debug builds.
+        let mut builder = syntect::parsing::SyntaxSetBuilder::new();
+        builder.add_plain_text_syntax();
+        let mut themes = ThemeSet::default();
+        themes
+            .themes
+            .insert("plain".to_string(), syntect::highlighting::Theme::default());
+        let (syntax_dump, theme_dump) = (dir.join("syntaxes.packdump"), dir.join("themes.themedump"));
+        dumps::dump_to_file(&builder.build(), &syntax_dump).unwrap();
+        dumps::dump_to_file(&themes, &theme_dump).unwrap();
+
+        let mut config = test_config();
+        config.syntax_highlighting.syntax_dump = Some(syntax_dump);
+        config.syntax_highlighting.theme_dump = Some(theme_dump);
+        config.syntax_highlighting.default_theme = "plain".to_string();
+        let renderer = Renderer::new(config).unwrap();
+        assert_eq!(renderer.syntaxes().syntaxes().len(), 1);
+        assert!(renderer.themes().themes.contains_key("plain"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut config = test_config();
+        config.syntax_highlighting.syntax_dump = Some(dir.join("missing.packdump"));
+        let renderer = Renderer::new(config).unwrap();
+        assert!(renderer.syntaxes().find_syntax_by_extension("rs").is_some());
+    }
+
+    #[test]
+    fn unknown_default_theme() {
+        let mut config = test_config();
+        config.syntax_highlighting.default_theme = "typo".to_string();
+        let Err(RenderError::ConfigError(message)) = Renderer::new(config) else {
+            panic!("expected a config error");
+        };
+        assert!(message.contains("'typo'"));
+        assert!(message.contains("base16-ocean.dark"));
+
+        let renderer = Renderer::new(test_config()).unwrap();
+        assert!(renderer.highlighting.themes.get().is_none());
+        assert_eq!(renderer.theme_names(), BUNDLED_THEMES);
+        assert!(matches!(renderer.theme("typo"), Err(RenderError::HighlightError(_))));
+    }
+
+    #[test]
+    fn bundled_theme_names() {
+        let bundled = ThemeSet::load_defaults();
+        assert_eq!(bundled.themes.keys().map(String::as_str).collect::<Vec<_>>(), BUNDLED_THEMES);
+    }
+
+    #[test]
+    fn custom_default_theme() {
+        const THEME: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+<key>name</key><string>Mini</string>
+<key>settings</key><array><dict><key>settings</key><dict><key>foreground</key><string>#000000</string></dict></dict></array>
+</dict></plist>"#;
+        let dir = std::env::temp_dir().join(format!("htmlua-themes-{}", std::process::id()));
+        let themes = dir.join("themes");
+        fs::create_dir_all(&themes).unwrap();
+        fs::write(themes.join("mini.tmTheme"), THEME).unwrap();
+        fs::write(themes.join("broken.tmTheme"), "not a theme").unwrap();
+        fs::write(dir.join("outside.tmTheme"), THEME).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside.tmTheme"), themes.join("outside.tmTheme")).unwrap();
+
+        let with_theme = |name: &str| {
+            let mut config = test_config();
+            config.paths.themes.clone_from(&themes);
+            config.syntax_highlighting.default_theme = name.to_string();
+            Renderer::new(config)
+        };
+        let renderer = with_theme("mini").unwrap();
+        assert!(renderer.highlighting.themes.get().is_none());
+        assert!(renderer.theme("mini").is_ok());
+        assert!(matches!(with_theme("broken"), Err(RenderError::ConfigError(_))));
+        assert!(matches!(with_theme("outside"), Err(RenderError::ConfigError(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fallbacks_replace_only_invalid_settings() {
+        let mut config = test_config();
+        config.templates.pipeline = vec!["lua".to_string(), "minify".to_string()];
+        config.syntax_highlighting.default_theme = "InspiredGitHub".to_string();
+        config.syntax_highlighting.css_classes = true;
+        let renderer = Renderer::with_fallbacks(config);
+        assert_eq!(renderer.config().templates.pipeline, Config::default().templates.pipeline);
+        assert_eq!(renderer.config().syntax_highlighting.default_theme, "InspiredGitHub");
+        assert!(renderer.config().syntax_highlighting.css_classes);
+
+        let mut config = test_config();
+        config.templates.pipeline = vec!["lua".to_string()];
+        config.syntax_highlighting.default_theme = "typo".to_string();
+        config.syntax_highlighting.css_classes = true;
+        let renderer = Renderer::with_fallbacks(config);
+        assert_eq!(renderer.config().templates.pipeline, ["lua"]);
+        assert_eq!(renderer.config().syntax_highlighting.default_theme, "base16-ocean.dark");
+        assert!(renderer.config().syntax_highlighting.css_classes);
+    }
+
+    #[test]
+    fn classed_highlighting_and_theme_css() {
+        let mut config = test_config();
+        config.syntax_highlighting.css_classes = true;
+        let renderer = Renderer::new(config).unwrap();
+        let page = "<div><syntaxhighlight lang=\"rs\">/* a\nb */ fn main() {}</syntaxhighlight></div>";
+        let body = renderer.render_str(page, &Request::new("GET", "/")).unwrap().body;
+        assert!(body.contains(r#"<pre class="syntax-highlight hl-code" data-lang="rs">"#));
+        assert!(body.contains(r#"<span class="hl-source hl-rust">"#));
+        assert!(!body.contains("style="));
+
+        let response = renderer
+            .serve(&Request::new("GET", "/_htmlua/theme/Solarized (dark).css"))
+            .unwrap();
+        assert_eq!(response.header("content-type"), Some("text/css; charset=utf-8"));
+        assert!(response.body.contains(".hl-code"));
+        assert!(matches!(
+            renderer.serve(&Request::new("GET", "/_htmlua/theme/typo.css")),
+            Err(RenderError::NotFound(_))
+        ));
+    }
+}
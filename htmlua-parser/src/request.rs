@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use mlua::{Lua, Table, Value};
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query_string: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    #[must_use]
+    pub fn new(method: &str, path: &str) -> Self {
+        Self {
+            method: method.to_ascii_uppercase(),
+            path: path.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// Adds a header, lowercasing the name and joining repeated headers with `", "`.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    #[must_use]
+    pub fn query(&self) -> HashMap<String, String> { parse_urlencoded(&self.query_string) }
+
+    /// Parses the body as `application/x-www-form-urlencoded`, or returns an empty map for any other content type.
+    #[must_use]
+    pub fn form(&self) -> HashMap<String, String> {
+        let is_form = self
+            .header("content-type")
+            .is_some_and(|t| t.starts_with("application/x-www-form-urlencoded"));
+        if is_form {
+            parse_urlencoded(&self.body)
+        } else {
+            HashMap::new()
+        }
+    }
+
+    #[must_use]
+    pub fn cookies(&self) -> HashMap<String, String> {
+        self.header("cookie")
+            .into_iter()
+            .flat_map(|c| c.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().trim_matches('"').to_string()))
+            .collect()
+    }
+
+    /// Builds the read-only `htmlua.request` table.
+    pub fn to_lua_table(&self, l: &Lua) -> mlua::Result<Table> {
+        let t = l.create_table()?;
+        t.set("method", self.method.as_str())?;
+        t.set("path", self.path.as_str())?;
+        t.set("query_string", self.query_string.as_str())?;
+        t.set("body", self.body.as_str())?;
+        t.set("headers", read_only_table(l, l.create_table_from(self.headers.clone())?)?)?;
+        t.set("query", read_only_table(l, l.create_table_from(self.query())?)?)?;
+        t.set("form", read_only_table(l, l.create_table_from(self.form())?)?)?;
+        t.set("cookies", read_only_table(l, l.create_table_from(self.cookies())?)?)?;
+        read_only_table(l, t)
+    }
+}
+
+fn parse_urlencoded(input: &str) -> HashMap<String, String> {
+    form_urlencoded::parse(input.as_bytes()).into_owned().collect()
+}
+
+/// Wraps `inner` in an empty proxy table whose metatable forwards reads and rejects writes.
+fn read_only_table(l: &Lua, inner: Table) -> mlua::Result<Table> {
+    let proxy = l.create_table()?;
+    let meta = l.create_table()?;
+    meta.set("__index", inner.clone())?;
+    meta.set(
+        "__newindex",
+        l.create_function(|_, (_, key, _): (Table, Value, Value)| -> mlua::Result<()> {
+            Err(mlua::Error::RuntimeError(format!("attempt to modify read-only request field '{}'", key.to_string()?)))
+        })?,
+    )?;
+    meta.set(
+        "__pairs",
+        l.create_function(move |l, _: Table| {
+            let next: mlua::Function = l.globals().get("next")?;
+            Ok((next, inner.clone(), Value::Nil))
+        })?,
+    )?;
+    meta.set("__metatable", false)?;
+    proxy.set_metatable(Some(meta));
+    Ok(proxy)
+}
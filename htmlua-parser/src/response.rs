@@ -0,0 +1,162 @@
+use std::fmt::Write;
+
+use thiserror::Error;
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/html; charset=utf-8".to_string())],
+            body: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CookieOptions {
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub max_age: Option<i64>,
+    pub expires: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<String>,
+}
+
+/// A header or cookie that would corrupt the response head, such as a value with a line break that smuggles in
+/// another header.
+#[derive(Debug, Error)]
+#[error("Invalid response header: {0}")]
+pub struct InvalidHeader(String);
+
+impl Response {
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Replaces every header called `name` with a single `name: value`.
+    pub fn set_header(&mut self, name: &str, value: &str) -> Result<(), InvalidHeader> {
+        check_header(name, value)?;
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+        self.add_header(name, value)
+    }
+
+    /// Adds a header. `name` must be an HTTP token and `value` can't contain CR, LF or NUL.
+    pub fn add_header(&mut self, name: &str, value: &str) -> Result<(), InvalidHeader> {
+        check_header(name, value)?;
+        self.headers.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    pub fn set_content_type(&mut self, content_type: &str) -> Result<(), InvalidHeader> {
+        self.set_header("Content-Type", content_type)
+    }
+
+    pub fn redirect(&mut self, location: &str, status: u16) -> Result<(), InvalidHeader> {
+        self.set_header("Location", location)?;
+        self.status = status;
+        Ok(())
+    }
+
+    /// Adds a `Set-Cookie` header. `name` must be an HTTP token, `value` can't contain `;` or `,`, and the
+    /// attributes can't contain `;`.
+    pub fn set_cookie(&mut self, name: &str, value: &str, options: &CookieOptions) -> Result<(), InvalidHeader> {
+        if !is_token(name) {
+            return Err(InvalidHeader(format!("'{name}' is not a valid cookie name")));
+        }
+        if value.contains([';', ',']) {
+            return Err(InvalidHeader(format!("the value of cookie {name} contains ';' or ','")));
+        }
+        let attributes = [&options.path, &options.domain, &options.expires, &options.same_site];
+        if attributes
+            .into_iter()
+            .flatten()
+            .any(|attribute| attribute.contains(';'))
+        {
+            return Err(InvalidHeader(format!("an attribute of cookie {name} contains ';'")));
+        }
+
+        let mut cookie = format!("{name}={value}");
+        if let Some(path) = &options.path {
+            let _ = write!(cookie, "; Path={path}");
+        }
+        if let Some(domain) = &options.domain {
+            let _ = write!(cookie, "; Domain={domain}");
+        }
+        if let Some(max_age) = options.max_age {
+            let _ = write!(cookie, "; Max-Age={max_age}");
+        }
+        if let Some(expires) = &options.expires {
+            let _ = write!(cookie, "; Expires={expires}");
+        }
+        if options.secure {
+            cookie.push_str("; Secure");
+        }
+        if options.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = &options.same_site {
+            let _ = write!(cookie, "; SameSite={same_site}");
+        }
+        self.add_header("Set-Cookie", &cookie)
+    }
+
+    #[must_use]
+    pub fn is_redirect(&self) -> bool { (300..400).contains(&self.status) && self.header("Location").is_some() }
+
+    #[must_use]
+    pub fn reason_phrase(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            201 => "Created",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            410 => "Gone",
+            422 => "Unprocessable Content",
+            429 => "Too Many Requests",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Whether `s` is a `token` as defined by RFC 9110, the form header and cookie names take.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+fn check_header(name: &str, value: &str) -> Result<(), InvalidHeader> {
+    if !is_token(name) {
+        return Err(InvalidHeader(format!("'{name}' is not a valid header name")));
+    }
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(InvalidHeader(format!("the value of {name} contains CR, LF or NUL")));
+    }
+    Ok(())
+}
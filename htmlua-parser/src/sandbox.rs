@@ -0,0 +1,289 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use mlua::{Debug, HookTriggers, Lua, LuaOptions, MultiValue, Result, StdLib, Thread, VmState};
+
+use crate::config::LuaConfig;
+
+/// How many VM instructions run between checks of the instruction and wall-clock limits.
+const HOOK_INTERVAL: u32 = 1000;
+
+const RESTRICT_GLOBALS: &str = r##"
+dofile = nil
+loadfile = nil
+local raw_load = load
+load = function(chunk, name, _, ...)
+    if select("#", ...) > 0 then
+        return raw_load(chunk, name, "t", ...)
+    end
+    return raw_load(chunk, name, "t")
+end
+package.loadlib = nil
+package.path = ""
+package.cpath = ""
+package.searchers = { package.searchers[1] }
+if os then
+    for _, f in ipairs({ "execute", "exit", "getenv", "remove", "rename", "setlocale", "tmpname" }) do
+        os[f] = nil
+    end
+end
+"##;
+
+/// Creates the Lua state for one render, applying the sandbox and resource limits from `config`.
+pub fn new_lua(config: &LuaConfig) -> Result<Lua> {
+    let lua = if config.sandbox {
+        let libs = config
+            .libraries
+            .iter()
+            .try_fold(StdLib::NONE, |libs, name| stdlib_from_name(name).map(|lib| libs | lib))?;
+        // `package` is always loaded so `require` can reach the lua_modules searcher; the sandbox script strips
+        // its filesystem and C searchers.
+        let lua = Lua::new_with(libs | StdLib::PACKAGE, LuaOptions::default())?;
+        lua.load(RESTRICT_GLOBALS).set_name("=htmlua sandbox").exec()?;
+        lua
+    } else {
+        Lua::new()
+    };
+    apply_limits(&lua, config)?;
+    Ok(lua)
+}
+
+fn stdlib_from_name(name: &str) -> Result<StdLib> {
+    match name {
+        "coroutine" => Ok(StdLib::COROUTINE),
+        "table" => Ok(StdLib::TABLE),
+        "io" => Ok(StdLib::IO),
+        "os" => Ok(StdLib::OS),
+        "string" => Ok(StdLib::STRING),
+        "utf8" => Ok(StdLib::UTF8),
+        "math" => Ok(StdLib::MATH),
+        "package" => Ok(StdLib::PACKAGE),
+        _ => Err(mlua::Error::RuntimeError(format!(
+            "Lua library '{name}' is unknown or cannot be enabled in the sandbox"
+        ))),
+    }
+}
+
+/// Re-raises a tripped limit where Lua could otherwise swallow it: after `pcall`, `xpcall` and `coroutine.resume`,
+/// which also moves the limit hook onto the resumed coroutine, since mlua only hooks one thread at a time.
+const LIMIT_GUARDS: &str = r"
+local check, hook_thread = ...
+local raw_pcall, raw_xpcall = pcall, xpcall
+pcall = function(...) return check(raw_pcall(...)) end
+xpcall = function(...) return check(raw_xpcall(...)) end
+if coroutine then
+    local raw_resume, create = coroutine.resume, coroutine.create
+    local function resume(co, ...)
+        hook_thread(co)
+        return check(raw_resume(co, ...))
+    end
+    local function unwrap(ok, ...)
+        if ok then return ... end
+        error((...), 0)
+    end
+    coroutine.resume = resume
+    coroutine.wrap = function(f)
+        local co = create(f)
+        return function(...) return unwrap(resume(co, ...)) end
+    end
+end
+";
+
+/// The instruction and wall-clock limits of one Lua state. Once one is exceeded it stays exceeded, so every later
+/// check fails too.
+struct Limits {
+    instruction_limit: u64,
+    timeout_ms: u64,
+    deadline: Option<Instant>,
+    executed: Cell<u64>,
+    exceeded: RefCell<Option<String>>,
+}
+
+impl Limits {
+    /// Counts `HOOK_INTERVAL` more instructions and checks both limits.
+    fn tick(&self) -> Result<VmState> {
+        self.check()?;
+        self.executed.set(self.executed.get() + u64::from(HOOK_INTERVAL));
+        let exceeded = if self.instruction_limit > 0 && self.executed.get() > self.instruction_limit {
+            format!("instruction limit of {} exceeded", self.instruction_limit)
+        } else if self.deadline.is_some_and(|d| Instant::now() > d) {
+            format!("timeout of {}ms exceeded", self.timeout_ms)
+        } else {
+            return Ok(VmState::Continue);
+        };
+        *self.exceeded.borrow_mut() = Some(exceeded);
+        self.check().map(|()| VmState::Continue)
+    }
+
+    fn check(&self) -> Result<()> {
+        match &*self.exceeded.borrow() {
+            Some(message) => Err(mlua::Error::RuntimeError(message.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn hook(self: &Rc<Self>) -> impl Fn(&Lua, Debug) -> Result<VmState> + 'static {
+        let limits = self.clone();
+        move |_, _| limits.tick()
+    }
+}
+
+fn apply_limits(lua: &Lua, config: &LuaConfig) -> Result<()> {
+    if config.memory_limit_bytes > 0 {
+        lua.set_memory_limit(config.memory_limit_bytes)?;
+    }
+    if config.instruction_limit == 0 && config.timeout_ms == 0 {
+        return Ok(());
+    }
+
+    let limits = Rc::new(Limits {
+        instruction_limit: config.instruction_limit,
+        timeout_ms: config.timeout_ms,
+        deadline: (config.timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(config.timeout_ms)),
+        executed: Cell::new(0),
+        exceeded: RefCell::new(None),
+    });
+    let triggers = HookTriggers::new().every_nth_instruction(HOOK_INTERVAL);
+    lua.set_hook(triggers, limits.hook());
+
+    let check = {
+        let limits = limits.clone();
+        lua.create_function(move |lua, results: MultiValue| {
+            // Back on the resuming thread, whose hook `hook_thread` took away.
+            lua.current_thread().set_hook(triggers, limits.hook());
+            limits.check().map(|()| results)
+        })?
+    };
+    let hook_thread = lua.create_function(move |_, thread: Thread| {
+        thread.set_hook(triggers, limits.hook());
+        Ok(())
+    })?;
+    lua.load(LIMIT_GUARDS)
+        .set_name("=htmlua limits")
+        .call::<()>((check, hook_thread))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox_config() -> LuaConfig {
+        LuaConfig {
+            timeout_ms: 0,
+            ..LuaConfig::default()
+        }
+    }
+
+    #[test]
+    fn sandbox_strips_unsafe_globals() {
+        let lua = new_lua(&sandbox_config()).unwrap();
+        let missing: bool = lua
+            .load("return io == nil and os.execute == nil and dofile == nil and loadfile == nil")
+            .eval()
+            .unwrap();
+        assert!(missing);
+        assert_eq!(
+            lua.load("return os.time() > 0 and string.upper('a')")
+                .eval::<String>()
+                .unwrap(),
+            "A"
+        );
+        assert_eq!(
+            lua.load("return load('return x', 'chunk', 'b', { x = 1 })()")
+                .eval::<i32>()
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn instruction_limit() {
+        let config = LuaConfig {
+            instruction_limit: 100_000,
+            ..sandbox_config()
+        };
+        let lua = new_lua(&config).unwrap();
+        let err = lua.load("while true do end").exec().unwrap_err();
+        assert!(err.to_string().contains("instruction limit"));
+    }
+
+    #[test]
+    fn timeout() {
+        let config = LuaConfig {
+            instruction_limit: 0,
+            timeout_ms: 50,
+            ..sandbox_config()
+        };
+        let lua = new_lua(&config).unwrap();
+        let err = lua.load("while true do end").exec().unwrap_err();
+        assert!(err.to_string().contains("timeout"));
+    }
+
+    #[test]
+    fn limits_survive_pcall() {
+        let config = LuaConfig {
+            timeout_ms: 50,
+            ..sandbox_config()
+        };
+        let lua = new_lua(&config).unwrap();
+        let err = lua
+            .load("while true do pcall(function() while true do end end) end")
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("timeout"));
+        assert!(lua.load("return pcall(print)").exec().is_err());
+    }
+
+    #[test]
+    fn limits_apply_to_coroutines() {
+        let config = LuaConfig {
+            timeout_ms: 50,
+            ..sandbox_config()
+        };
+        let lua = new_lua(&config).unwrap();
+        let err = lua
+            .load("coroutine.wrap(function() while true do end end)()")
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("timeout"));
+
+        let lua = new_lua(&config).unwrap();
+        let code = r"
+            local co = coroutine.create(function() while true do end end)
+            while true do coroutine.resume(co) end
+        ";
+        assert!(lua.load(code).exec().unwrap_err().to_string().contains("timeout"));
+
+        let lua = new_lua(&config).unwrap();
+        let code = r"
+            local gen = coroutine.wrap(function(a) local b = coroutine.yield(a + 1) return b * 2 end)
+            local co = coroutine.create(function() error({ code = 7 }) end)
+            local ok, err = coroutine.resume(co)
+            return gen(1), gen(5), ok, err.code
+        ";
+        let (first, second, ok, code): (i32, i32, bool, i32) = lua.load(code).eval().unwrap();
+        assert_eq!((first, second, ok, code), (2, 10, false, 7));
+    }
+
+    #[test]
+    fn memory_limit() {
+        let config = LuaConfig {
+            memory_limit_bytes: 1024 * 1024,
+            ..sandbox_config()
+        };
+        let lua = new_lua(&config).unwrap();
+        assert!(lua.load("local t = {} for i = 1, 1e7 do t[i] = i end").exec().is_err());
+    }
+
+    #[test]
+    fn unknown_library() {
+        let config = LuaConfig {
+            libraries: vec!["debug".to_string()],
+            ..sandbox_config()
+        };
+        assert!(new_lua(&config).is_err());
+    }
+}
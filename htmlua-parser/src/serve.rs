@@ -1,11 +1,11 @@
-use std::{path::Path, sync::OnceLock};
-
-use anyhow::{Result, anyhow};
+use std::sync::OnceLock;
 
 use crate::{
     config::Config,
-    helpers::read_doc_from_file,
-    render::{execute_lua, expand_template, process_markdown, process_syntax_highlighting},
+    error::{RenderError, Result},
+    renderer::Renderer,
+    request::Request,
+    response::Response,
 };
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
@@ -20,16 +20,10 @@ pub fn get_config() -> &'static Config {
     })
 }
 
-pub fn serve_content(request_uri: &str) -> Result<String> {
-    let config = get_config();
-    let safe_path = Path::new(request_uri).strip_prefix("/")?;
-    let page_path = config.paths.pages.join(safe_path);
-    let doc = read_doc_from_file(page_path)?
-        .select_first("html")
-        .map_err(|()| anyhow!("failed to read doc"))?;
-    let full_doc = expand_template(doc.as_node().to_owned(), &config.paths.components, None)?;
-    let markdown_doc = process_markdown(full_doc)?;
-    let highlighted_doc = process_syntax_highlighting(markdown_doc)?;
-    let executed_doc = execute_lua(highlighted_doc)?;
-    Ok(executed_doc.to_string())
-}
+/// Renders a page with [`Renderer::global`], which has the built-in tags only; custom tags need a [`Renderer`] built
+/// with them.
+pub fn serve_content(request: &Request) -> Result<Response> { Renderer::global().serve(request) }
+
+/// Builds the error response for a failed render with [`Renderer::global`].
+#[must_use]
+pub fn error_response(error: &RenderError) -> Response { Renderer::global().error_response(error) }
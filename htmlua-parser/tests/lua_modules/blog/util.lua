@@ -0,0 +1,10 @@
+local M = {}
+
+loads = (loads or 0) + 1
+M.loads = loads
+
+function M.slug(text)
+  return (text:lower():gsub("%s+", "-"))
+end
+
+return M
@@ -0,0 +1,14 @@
+use std::fmt;
+
+// region: point
+struct Point {
+    x: i32,
+    y: i32,
+}
+// endregion
+
+impl fmt::Display for Point {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
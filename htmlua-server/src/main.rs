@@ -1,3 +1,158 @@
-fn main() {
-    println!("Hello, world!");
+use std::{
+    env,
+    io::{self, Read},
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{Result, anyhow};
+use htmlua_parser::{renderer::Renderer, request::Request as HtmluaRequest, serve::get_config};
+use percent_encoding::percent_decode_str;
+use tiny_http::{Header, Request, Response, Server, StatusCode};
+
+fn main() -> Result<()> {
+    let config = get_config();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => {}
+        ["themes", "list"] => {
+            let renderer = Renderer::builder().config(config.clone()).build()?;
+            for name in renderer.theme_names() {
+                println!("{name}");
+            }
+            return Ok(());
+        }
+        ["themes", "css", name] => {
+            let renderer = Renderer::builder().config(config.clone()).build()?;
+            print!("{}", renderer.theme_css(name)?);
+            return Ok(());
+        }
+        _ => return Err(anyhow!("Usage: htmlua-server [themes list | themes css <name>]")),
+    }
+
+    let address = format!("{}:{}", config.server.host, config.server.port);
+    let server = Arc::new(Server::http(&address).map_err(|e| anyhow!("Failed to bind {address}: {e}"))?);
+    println!("htmlua-server listening on http://{address}");
+    let renderer = Renderer::builder().config(config.clone()).build()?;
+
+    let workers = thread::available_parallelism().map_or(4, std::num::NonZero::get);
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let server = server.clone();
+            let renderer = renderer.clone();
+            thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle_request(&renderer, request);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().map_err(|_| anyhow!("Worker thread panicked"))?;
+    }
+    Ok(())
+}
+
+fn handle_request(renderer: &Renderer, mut request: Request) {
+    let htmlua_request = match to_htmlua_request(&mut request, renderer.config().server.max_body_bytes) {
+        Ok(htmlua_request) => htmlua_request,
+        Err(status) => {
+            let url = request.url().to_string();
+            println!("{} {url} {}", request.method(), status.0);
+            let response = Response::from_string(status.default_reason_phrase()).with_status_code(status);
+            if let Err(e) = request.respond(response) {
+                eprintln!("Failed to send response for {url}: {e}");
+            }
+            return;
+        }
+    };
+    let path = htmlua_request.path.clone();
+    let page = renderer.serve(&htmlua_request).unwrap_or_else(|e| {
+        eprintln!("{} {path}: {e}", request.method());
+        renderer.error_response(&e)
+    });
+    println!("{} {path} {}", request.method(), page.status);
+
+    let mut response = Response::from_string(page.body).with_status_code(StatusCode(page.status));
+    for (name, value) in page.headers {
+        match Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            Ok(header) => response.add_header(header),
+            Err(()) => eprintln!("Dropping invalid response header {name:?} for {path}"),
+        }
+    }
+    if let Err(e) = request.respond(response) {
+        eprintln!("Failed to send response for {path}: {e}");
+    }
+}
+
+/// Maps a request to htmlua's, or to the status it is refused with: 413 for a body over `max_body_bytes`, 400 for
+/// one that can't be read.
+fn to_htmlua_request(request: &mut Request, max_body_bytes: u64) -> Result<HtmluaRequest, StatusCode> {
+    let url = request.url().to_string();
+    let (path, query_string) = url.split_once('?').unwrap_or((&url, ""));
+    // Decoded like CGI's `PATH_INFO`; `resolve_within` still rejects any `..` this produces.
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    let mut htmlua_request = HtmluaRequest::new(request.method().as_str(), &path);
+    htmlua_request.query_string = query_string.to_string();
+    for header in request.headers() {
+        htmlua_request.add_header(header.field.as_str().as_str(), header.value.as_str());
+    }
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > max_body_bytes)
+    {
+        return Err(StatusCode(413));
+    }
+    htmlua_request.body = match read_body(request.as_reader(), max_body_bytes) {
+        Ok(Some(body)) => body,
+        Ok(None) => return Err(StatusCode(413)),
+        Err(e) => {
+            eprintln!("Failed to read request body for {path}: {e}");
+            return Err(StatusCode(400));
+        }
+    };
+    Ok(htmlua_request)
+}
+
+/// Reads a body of at most `limit` bytes, replacing invalid UTF-8 like the CGI frontend does. `None` when it is
+/// longer.
+fn read_body(reader: &mut dyn Read, limit: u64) -> io::Result<Option<String>> {
+    let mut body = Vec::new();
+    reader.take(limit.saturating_add(1)).read_to_end(&mut body)?;
+    if body.len() as u64 > limit {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tiny_http::{Method, TestRequest};
+
+    use super::*;
+
+    #[test]
+    fn maps_requests() {
+        let mut request: Request = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path("/blog/caf%C3%A9%20menu.html?q=a%20b")
+            .with_header("X-Test: 1".parse().unwrap())
+            .with_body("name=ada")
+            .into();
+        let htmlua_request = to_htmlua_request(&mut request, 1024).unwrap();
+        assert_eq!(htmlua_request.method, "POST");
+        assert_eq!(htmlua_request.path, "/blog/café menu.html");
+        assert_eq!(htmlua_request.query_string, "q=a%20b");
+        assert_eq!(htmlua_request.header("x-test"), Some("1"));
+        assert_eq!(htmlua_request.body, "name=ada");
+    }
+
+    #[test]
+    fn limits_request_bodies() {
+        let mut request: Request = TestRequest::new().with_body("0123456789").into();
+        assert_eq!(to_htmlua_request(&mut request, 9).unwrap_err(), StatusCode(413));
+        assert_eq!(read_body(&mut &b"0123456789"[..], 9).unwrap(), None);
+        assert_eq!(read_body(&mut &b"a\xffb"[..], 9).unwrap().as_deref(), Some("a\u{fffd}b"));
+    }
 }